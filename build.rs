@@ -0,0 +1,24 @@
+//! Captures the git commit the worker is built from, reported by `/version`.
+//! `GIT_HASH` overrides it where the build runs outside of a git checkout.
+
+use std::{env, process::Command};
+
+fn main() {
+    let git_hash = env::var("GIT_HASH")
+        .ok()
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())?;
+            String::from_utf8(output.stdout)
+                .ok()
+                .map(|hash| hash.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
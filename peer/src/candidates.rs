@@ -0,0 +1,130 @@
+use crate::peer_connection::PeerConnection;
+use std::collections::VecDeque;
+use web_sys::RtcSdpType;
+
+/// Buffers ICE candidates which arrive before the remote description is set,
+/// adding them to a peer connection too early makes it reject them.
+#[derive(Debug, Default)]
+pub(crate) struct PendingCandidates {
+    remote_description_set: bool,
+    queue: VecDeque<String>,
+}
+
+impl PendingCandidates {
+    /// Adds a candidate to the peer connection, or buffers it until the remote description is set.
+    pub(crate) async fn add<P: PeerConnection>(
+        &mut self,
+        pc: &P,
+        candidate: String,
+    ) -> Result<(), P::Error> {
+        if !self.remote_description_set {
+            self.queue.push_back(candidate);
+            return Ok(());
+        }
+        pc.add_ice_candidate(&candidate).await
+    }
+
+    /// Sets the remote description, then flushes buffered candidates in the order they arrived.
+    /// Flushing stops at the first candidate failed to be added, the rest are kept buffered.
+    pub(crate) async fn set_remote_description<P: PeerConnection>(
+        &mut self,
+        pc: &P,
+        sdp_type: RtcSdpType,
+        sdp: &str,
+    ) -> Result<(), P::Error> {
+        pc.set_remote_description(sdp_type, sdp).await?;
+        self.remote_description_set = true;
+
+        while let Some(candidate) = self.queue.front() {
+            pc.add_ice_candidate(candidate).await?;
+            self.queue.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PendingCandidates;
+    use crate::peer_connection::PeerConnection;
+    use futures::executor::block_on;
+    use std::cell::{Cell, RefCell};
+    use web_sys::RtcSdpType;
+
+    /// Mimics a browser peer connection, which rejects candidates before remote description is set.
+    #[derive(Default)]
+    struct MockPeerConnection {
+        remote_description: RefCell<Option<String>>,
+        candidates: RefCell<Vec<String>>,
+        reject_remote_description: Cell<bool>,
+    }
+
+    impl PeerConnection for MockPeerConnection {
+        type Error = String;
+
+        async fn set_remote_description(
+            &self,
+            _sdp_type: RtcSdpType,
+            sdp: &str,
+        ) -> Result<(), String> {
+            if self.reject_remote_description.get() {
+                return Err("invalid sdp".into());
+            }
+            self.remote_description.replace(Some(sdp.into()));
+            Ok(())
+        }
+
+        async fn add_ice_candidate(&self, candidate: &str) -> Result<(), String> {
+            if self.remote_description.borrow().is_none() {
+                return Err("remote description is not set".into());
+            }
+            self.candidates.borrow_mut().push(candidate.into());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn buffer_early_candidates() {
+        let pc = MockPeerConnection::default();
+        let mut pending = PendingCandidates::default();
+
+        block_on(async {
+            pending.add(&pc, "a".into()).await.unwrap();
+            pending.add(&pc, "b".into()).await.unwrap();
+            assert!(pc.candidates.borrow().is_empty());
+
+            pending
+                .set_remote_description(&pc, RtcSdpType::Offer, "sdp")
+                .await
+                .unwrap();
+            assert_eq!(*pc.candidates.borrow(), ["a", "b"]);
+
+            pending.add(&pc, "c".into()).await.unwrap();
+            assert_eq!(*pc.candidates.borrow(), ["a", "b", "c"]);
+        });
+    }
+
+    #[test]
+    fn keep_candidates_if_remote_description_rejected() {
+        let pc = MockPeerConnection::default();
+        pc.reject_remote_description.set(true);
+        let mut pending = PendingCandidates::default();
+
+        block_on(async {
+            pending.add(&pc, "a".into()).await.unwrap();
+            assert!(pending
+                .set_remote_description(&pc, RtcSdpType::Answer, "sdp")
+                .await
+                .is_err());
+            pending.add(&pc, "b".into()).await.unwrap();
+            assert!(pc.candidates.borrow().is_empty());
+
+            pc.reject_remote_description.set(false);
+            pending
+                .set_remote_description(&pc, RtcSdpType::Answer, "sdp")
+                .await
+                .unwrap();
+            assert_eq!(*pc.candidates.borrow(), ["a", "b"]);
+        });
+    }
+}
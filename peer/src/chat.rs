@@ -0,0 +1,126 @@
+//! Text chat over a reliable data channel alongside the media.
+
+use crate::{console_error, console_log};
+use js_sys::{Date, Function};
+use protocol::{
+    chat::{ChatMessage, Envelope},
+    Role,
+};
+use std::cell::RefCell;
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{MessageEvent, RtcDataChannel, RtcDataChannelInit, RtcPeerConnection};
+
+const CHAT_CHANNEL_LABEL: &str = "chat";
+
+/// Both peers create the chat channel with this id, so it doesn't have to be announced in-band.
+const CHAT_CHANNEL_ID: u16 = 0;
+
+thread_local! {
+    static CHAT: RefCell<Chat> = const {
+        RefCell::new(Chat {
+            channel: None,
+            role: None,
+            callback: None,
+        })
+    };
+}
+
+struct Chat {
+    channel: Option<RtcDataChannel>,
+    /// Role of this peer, known once signal server assigns it.
+    role: Option<Role>,
+    /// A JS function called with every chat message sent or received.
+    callback: Option<Function>,
+}
+
+/// Opens the chat channel on a peer connection, it must be done before negotiation.
+pub(crate) fn open(pc: &RtcPeerConnection) {
+    let mut init = RtcDataChannelInit::new();
+    init.negotiated(true).id(CHAT_CHANNEL_ID).ordered(true);
+    let channel = pc.create_data_channel_with_data_channel_dict(CHAT_CHANNEL_LABEL, &init);
+
+    let onmessage_callback =
+        Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| match e.data().as_string() {
+            Some(data) => receive(&data),
+            None => console_log!("chat channel, received Unknown: {:?}", e.data()),
+        });
+    channel.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+    onmessage_callback.forget();
+
+    CHAT.with(|chat| chat.borrow_mut().channel = Some(channel));
+}
+
+/// Sets the role of this peer, chat messages sent from now on carry it.
+pub(crate) fn set_role(role: Role) {
+    CHAT.with(|chat| chat.borrow_mut().role = Some(role));
+}
+
+/// Sends a chat message to the other party.
+#[wasm_bindgen]
+pub fn send_chat(text: &str) -> Result<(), JsValue> {
+    let message = CHAT.with(|chat| {
+        let chat = chat.borrow();
+        let channel = chat.channel.as_ref().ok_or("chat is not open")?;
+        let message = ChatMessage {
+            sender: chat.role.ok_or("role is not assigned yet")?,
+            timestamp: Date::now() as u64,
+            text: text.into(),
+        };
+        let envelope = Envelope::Chat(message.clone());
+        channel.send_with_str(&serde_json::to_string(&envelope).unwrap())?;
+        Ok::<_, JsValue>(message)
+    })?;
+    deliver(&message, true);
+    Ok(())
+}
+
+/// Registers a JS function called with `(sender, timestamp, text, local)` for every chat message,
+/// `sender` is either "Caller" or "Callee", `local` tells whether the message is sent by this peer.
+#[wasm_bindgen]
+pub fn on_chat(callback: Function) {
+    CHAT.with(|chat| chat.borrow_mut().callback = Some(callback));
+}
+
+fn receive(data: &str) {
+    match serde_json::from_str::<Envelope>(data) {
+        Ok(Envelope::Chat(message)) => deliver(&message, false),
+        Err(err) => console_error!("invalid chat envelope: {}", err),
+    }
+}
+
+/// Renders a chat message in the page and hands it to the registered callback.
+fn deliver(message: &ChatMessage, local: bool) {
+    render(message);
+
+    let callback = CHAT.with(|chat| chat.borrow().callback.clone());
+    if let Some(callback) = callback {
+        let args = [
+            JsValue::from_str(&format!("{:?}", message.sender)),
+            JsValue::from_f64(message.timestamp as f64),
+            JsValue::from_str(&message.text),
+            JsValue::from_bool(local),
+        ];
+        if let Err(err) = callback.apply(&JsValue::NULL, &args.iter().collect()) {
+            console_error!("chat callback failed: {:?}", err);
+        }
+    }
+}
+
+fn render(message: &ChatMessage) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let chat_log = match document.get_element_by_id("chatLog") {
+        Some(chat_log) => chat_log,
+        None => return,
+    };
+    let time =
+        Date::new(&JsValue::from_f64(message.timestamp as f64)).to_locale_time_string("default");
+    let line = document.create_element("p").unwrap();
+    // Set as text content, never as HTML, since the other party controls the text.
+    line.set_text_content(Some(&format!(
+        "[{}] {:?}: {}",
+        String::from(time),
+        message.sender,
+        message.text
+    )));
+    chat_log.append_child(&line).unwrap();
+}
@@ -0,0 +1,242 @@
+//! End-to-end encryption of encoded media frames with a key derived from the signaling key exchange.
+//!
+//! The base key is the media key of `pake::SignalCipher`, every key epoch has its own key derived from
+//! the base key with HKDF, so rotating keys needs no exchange between peers. An encrypted frame is laid out as:
+//!
+//! ```text
+//! | unencrypted header | AES-GCM ciphertext and tag | IV (12 bytes) | key epoch (4 bytes) |
+//! ```
+//!
+//! The unencrypted header is the part of a frame that packetizers and decoders need to inspect,
+//! it is authenticated as additional data.
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::{collections::VecDeque, fmt};
+
+const HKDF_INFO: &[u8] = b"hangout-e2ee-epoch";
+
+const IV_LEN: usize = 12;
+const EPOCH_LEN: usize = 4;
+const TRAILER_LEN: usize = IV_LEN + EPOCH_LEN;
+
+/// Number of most recent epoch keys kept for frames still in flight after a rotation.
+const KEY_RING_SIZE: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum E2eeError {
+    /// The frame is too short to carry a trailer.
+    Truncated,
+    /// The frame is not encrypted with the same key, or is tampered with.
+    Decryption,
+}
+
+/// Encrypts outgoing frames and decrypts incoming frames of a call.
+pub(crate) struct FrameCryptor {
+    base_key: [u8; 32],
+    epoch: u32,
+    /// Random prefix of IVs generated by this cryptor, so that peers sharing a key don't reuse an IV.
+    iv_prefix: [u8; 4],
+    /// Frames encrypted so far, the rest of every IV. It runs across epochs, so an IV is never reused.
+    counter: u64,
+    /// Keys of recent epochs, most recent first.
+    keys: VecDeque<(u32, Aes256Gcm)>,
+}
+
+impl FrameCryptor {
+    pub(crate) fn new(base_key: [u8; 32], iv_prefix: [u8; 4]) -> FrameCryptor {
+        let mut cryptor = FrameCryptor {
+            base_key,
+            epoch: 0,
+            iv_prefix,
+            counter: 0,
+            keys: VecDeque::new(),
+        };
+        cryptor.key(0);
+        cryptor
+    }
+
+    /// Current key epoch used to encrypt frames.
+    pub(crate) fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Moves encryption on to the key of the next epoch. Epochs never wrap, the last one is kept.
+    pub(crate) fn rotate(&mut self) {
+        if let Some(epoch) = self.epoch.checked_add(1) {
+            self.epoch = epoch;
+            self.key(epoch);
+        }
+    }
+
+    /// Encrypts a frame, leaving its first `header_len` bytes unencrypted.
+    pub(crate) fn encrypt(&mut self, frame: &[u8], header_len: usize) -> Vec<u8> {
+        let mut iv = [0; IV_LEN];
+        iv[..4].copy_from_slice(&self.iv_prefix);
+        iv[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .expect("a cryptor never encrypts 2^64 frames");
+
+        let epoch = self.epoch;
+        let (header, payload) = frame.split_at(header_len.min(frame.len()));
+        let ciphertext = self
+            .key(epoch)
+            .encrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: payload,
+                    aad: header,
+                },
+            )
+            .expect("encryption of a frame never fails");
+
+        let mut encrypted = Vec::with_capacity(frame.len() + ciphertext.len() + TRAILER_LEN);
+        encrypted.extend_from_slice(header);
+        encrypted.extend_from_slice(&ciphertext);
+        encrypted.extend_from_slice(&iv);
+        encrypted.extend_from_slice(&epoch.to_be_bytes());
+        encrypted
+    }
+
+    /// Decrypts a frame encrypted with the same key and header length.
+    pub(crate) fn decrypt(
+        &mut self,
+        frame: &[u8],
+        header_len: usize,
+    ) -> Result<Vec<u8>, E2eeError> {
+        if frame.len() < header_len + TRAILER_LEN {
+            return Err(E2eeError::Truncated);
+        }
+        let (rest, trailer) = frame.split_at(frame.len() - TRAILER_LEN);
+        let (header, ciphertext) = rest.split_at(header_len);
+        let (iv, epoch) = trailer.split_at(IV_LEN);
+        let epoch = u32::from_be_bytes(epoch.try_into().expect("epochs are 4 bytes"));
+
+        let payload = self
+            .key(epoch)
+            .decrypt(
+                Nonce::from_slice(iv),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| E2eeError::Decryption)?;
+
+        let mut decrypted = Vec::with_capacity(header.len() + payload.len());
+        decrypted.extend_from_slice(header);
+        decrypted.extend_from_slice(&payload);
+        Ok(decrypted)
+    }
+
+    /// Returns the key of an epoch, derived and kept in key ring on first use.
+    fn key(&mut self, epoch: u32) -> &Aes256Gcm {
+        let index = match self.keys.iter().position(|(e, _)| *e == epoch) {
+            Some(index) => index,
+            None => {
+                let mut key = [0; 32];
+                Hkdf::<Sha256>::new(None, &self.base_key)
+                    .expand_multi_info(&[HKDF_INFO, &epoch.to_be_bytes()], &mut key)
+                    .expect("32 bytes is a valid HKDF output length");
+                self.keys.push_front((epoch, Aes256Gcm::new(&key.into())));
+                self.keys.truncate(KEY_RING_SIZE);
+                0
+            }
+        };
+        &self.keys[index].1
+    }
+}
+
+impl fmt::Display for E2eeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            E2eeError::Truncated => write!(f, "frame is too short to be encrypted"),
+            E2eeError::Decryption => write!(f, "frame could not be decrypted"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{E2eeError, FrameCryptor};
+
+    fn cryptors(key: [u8; 32]) -> (FrameCryptor, FrameCryptor) {
+        (
+            FrameCryptor::new(key, [0, 0, 0, 1]),
+            FrameCryptor::new(key, [0, 0, 0, 2]),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut caller, mut callee) = cryptors([1; 32]);
+        let frame = b"0123456789 encoded video frame";
+
+        let encrypted = caller.encrypt(frame, 10);
+        assert_eq!(&encrypted[..10], &frame[..10]);
+        assert_ne!(&encrypted[10..frame.len()], &frame[10..]);
+        assert_eq!(callee.decrypt(&encrypted, 10).unwrap(), frame);
+    }
+
+    #[test]
+    fn unique_ivs() {
+        let (mut caller, mut callee) = cryptors([1; 32]);
+        let a = caller.encrypt(b"frame", 1);
+        let b = caller.encrypt(b"frame", 1);
+        let c = callee.encrypt(b"frame", 1);
+        assert_ne!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let (mut caller, _) = cryptors([1; 32]);
+        let (_, mut eavesdropper) = cryptors([2; 32]);
+        let encrypted = caller.encrypt(b"frame", 1);
+        assert_eq!(
+            eavesdropper.decrypt(&encrypted, 1),
+            Err(E2eeError::Decryption)
+        );
+    }
+
+    #[test]
+    fn tampered_header() {
+        let (mut caller, mut callee) = cryptors([1; 32]);
+        let mut encrypted = caller.encrypt(b"frame", 1);
+        encrypted[0] ^= 1;
+        assert_eq!(callee.decrypt(&encrypted, 1), Err(E2eeError::Decryption));
+        assert_eq!(callee.decrypt(&[0; 5], 1), Err(E2eeError::Truncated));
+    }
+
+    #[test]
+    fn key_rotation() {
+        let (mut caller, mut callee) = cryptors([1; 32]);
+        let before = caller.encrypt(b"before", 1);
+        caller.rotate();
+        assert_eq!(caller.epoch(), 1);
+        let after = caller.encrypt(b"after", 1);
+
+        // Receiver follows rotations on its own, and still decrypts frames in flight.
+        assert_eq!(callee.decrypt(&after, 1).unwrap(), b"after");
+        assert_eq!(callee.decrypt(&before, 1).unwrap(), b"before");
+    }
+
+    #[test]
+    fn epochs_never_wrap() {
+        let (mut caller, mut callee) = cryptors([1; 32]);
+        caller.epoch = u32::MAX - 1;
+        caller.rotate();
+        let last = caller.encrypt(b"frame", 1);
+        caller.rotate();
+        assert_eq!(caller.epoch(), u32::MAX);
+        // The IV counter runs on, frames of the last epoch don't reuse IVs.
+        assert_ne!(caller.encrypt(b"frame", 1), last);
+        assert_eq!(callee.decrypt(&last, 1).unwrap(), b"frame");
+    }
+}
@@ -0,0 +1,182 @@
+//! Media encryption through insertable streams, encoded frames of every track pass through
+//! a `FrameCryptor` between encoder and packetizer, and between depacketizer and decoder.
+//!
+//! E2EE is opt-in with the `e2ee` query parameter of the page, and needs a browser
+//! supporting `createEncodedStreams()` on RTP senders and receivers.
+
+use crate::{console_error, console_log, e2ee::FrameCryptor};
+use js_sys::{ArrayBuffer, Function, Object, Reflect, Uint8Array};
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{RtcConfiguration, RtcRtpReceiver, RtcRtpSender, UrlSearchParams};
+
+/// Bytes of a VP8 key frame left unencrypted, payload header and key frame header.
+const KEY_FRAME_HEADER_LEN: usize = 10;
+/// Bytes of a VP8 delta frame left unencrypted, payload header.
+const DELTA_FRAME_HEADER_LEN: usize = 3;
+/// Bytes of an Opus frame left unencrypted, TOC byte.
+const AUDIO_FRAME_HEADER_LEN: usize = 1;
+
+/// Outgoing frames are encrypted with a new epoch key this often.
+const KEY_ROTATION_INTERVAL_MS: i32 = 60_000;
+
+/// Encrypts and decrypts encoded frames of a call, once the key exchange of the call gives it a key.
+#[derive(Clone)]
+pub(crate) struct EncodedTransform {
+    /// None until keyed, frames are dropped meanwhile.
+    cryptor: Rc<RefCell<Option<FrameCryptor>>>,
+}
+
+/// Tells whether E2EE is asked for by the page.
+pub(crate) fn requested() -> bool {
+    let search = web_sys::window()
+        .unwrap()
+        .location()
+        .search()
+        .unwrap_or_default();
+    UrlSearchParams::new_with_str(&search)
+        .map(|params| params.has("e2ee"))
+        .unwrap_or(false)
+}
+
+/// Tells whether the browser supports insertable streams.
+pub(crate) fn supported() -> bool {
+    let prototype = Reflect::get(&js_sys::global(), &"RTCRtpSender".into())
+        .and_then(|sender| Reflect::get(&sender, &"prototype".into()));
+    match prototype {
+        Ok(prototype) => Reflect::has(&prototype, &"createEncodedStreams".into()).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Makes a peer connection expose encoded streams, it must be set before the connection is created.
+pub(crate) fn configure(rtc_configuration: &RtcConfiguration) -> Result<(), JsValue> {
+    Reflect::set(
+        rtc_configuration,
+        &"encodedInsertableStreams".into(),
+        &JsValue::TRUE,
+    )?;
+    Ok(())
+}
+
+impl EncodedTransform {
+    /// Creates a transform without a key, and rotates its key periodically once it has one.
+    pub(crate) fn new() -> Result<EncodedTransform, JsValue> {
+        let cryptor = Rc::new(RefCell::new(None::<FrameCryptor>));
+
+        let rotated = cryptor.clone();
+        let rotate_callback = Closure::<dyn FnMut()>::new(move || {
+            if let Some(cryptor) = rotated.borrow_mut().as_mut() {
+                cryptor.rotate();
+                console_log!("rotated media key, epoch {}", cryptor.epoch());
+            }
+        });
+        web_sys::window()
+            .unwrap()
+            .set_interval_with_callback_and_timeout_and_arguments_0(
+                rotate_callback.as_ref().unchecked_ref(),
+                KEY_ROTATION_INTERVAL_MS,
+            )?;
+        rotate_callback.forget();
+
+        Ok(EncodedTransform { cryptor })
+    }
+
+    /// Keys the transform with the media key of an exchange, replacing the key of a previous one.
+    pub(crate) fn set_key(&self, base_key: [u8; 32]) {
+        let mut iv_prefix = [0; 4];
+        getrandom::getrandom(&mut iv_prefix).expect("could not generate random bytes");
+        *self.cryptor.borrow_mut() = Some(FrameCryptor::new(base_key, iv_prefix));
+    }
+
+    /// Encrypts every frame sent by a sender.
+    pub(crate) fn attach_sender(&self, sender: &RtcRtpSender) -> Result<(), JsValue> {
+        let cryptor = self.cryptor.clone();
+        attach(sender, move |data, header_len| {
+            let mut cryptor = cryptor.borrow_mut();
+            Some(cryptor.as_mut()?.encrypt(data, header_len))
+        })
+    }
+
+    /// Decrypts every frame received by a receiver, frames that fail to decrypt are dropped.
+    pub(crate) fn attach_receiver(&self, receiver: &RtcRtpReceiver) -> Result<(), JsValue> {
+        let cryptor = self.cryptor.clone();
+        attach(receiver, move |data, header_len| {
+            let mut cryptor = cryptor.borrow_mut();
+            match cryptor.as_mut()?.decrypt(data, header_len) {
+                Ok(frame) => Some(frame),
+                Err(err) => {
+                    set_indicator("not encrypted, check the passphrase of the other party");
+                    console_error!("dropped a media frame: {}", err);
+                    None
+                }
+            }
+        })
+    }
+}
+
+/// Shows whether media is end-to-end encrypted.
+pub(crate) fn set_indicator(text: &str) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    if let Some(indicator) = document.get_element_by_id("e2eeIndicator") {
+        indicator.set_text_content(Some(text));
+    }
+}
+
+/// Pipes the encoded streams of a sender or receiver through a function transforming frame data.
+fn attach(
+    endpoint: &JsValue,
+    mut transform: impl FnMut(&[u8], usize) -> Option<Vec<u8>> + 'static,
+) -> Result<(), JsValue> {
+    let create_encoded_streams: Function =
+        Reflect::get(endpoint, &"createEncodedStreams".into())?.dyn_into()?;
+    let streams = create_encoded_streams.call0(endpoint)?;
+    let readable = Reflect::get(&streams, &"readable".into())?;
+    let writable = Reflect::get(&streams, &"writable".into())?;
+
+    let transform_callback = Closure::<dyn FnMut(JsValue, JsValue) -> Result<(), JsValue>>::new(
+        move |frame: JsValue, controller: JsValue| {
+            let data: ArrayBuffer = Reflect::get(&frame, &"data".into())?.dyn_into()?;
+            let data = Uint8Array::new(&data).to_vec();
+            if let Some(data) = transform(&data, header_len(&frame)) {
+                let buffer = Uint8Array::from(data.as_slice()).buffer();
+                Reflect::set(&frame, &"data".into(), &buffer)?;
+                let enqueue: Function = Reflect::get(&controller, &"enqueue".into())?.dyn_into()?;
+                enqueue.call1(&controller, &frame)?;
+            }
+            Ok(())
+        },
+    );
+    let transformer = Object::new();
+    Reflect::set(
+        &transformer,
+        &"transform".into(),
+        transform_callback.as_ref(),
+    )?;
+    transform_callback.forget();
+
+    let transform_stream_class: Function =
+        Reflect::get(&js_sys::global(), &"TransformStream".into())?.dyn_into()?;
+    let transform_stream =
+        Reflect::construct(&transform_stream_class, &js_sys::Array::of1(&transformer))?;
+
+    let pipe_through: Function = Reflect::get(&readable, &"pipeThrough".into())?.dyn_into()?;
+    let transformed = pipe_through.call1(&readable, &transform_stream)?;
+    let pipe_to: Function = Reflect::get(&transformed, &"pipeTo".into())?.dyn_into()?;
+    pipe_to.call1(&transformed, &writable)?;
+    Ok(())
+}
+
+/// Number of leading bytes of an encoded frame that must stay readable by packetizers.
+fn header_len(frame: &JsValue) -> usize {
+    match Reflect::get(frame, &"type".into())
+        .ok()
+        .and_then(|t| t.as_string())
+        .as_deref()
+    {
+        Some("key") => KEY_FRAME_HEADER_LEN,
+        Some(_) => DELTA_FRAME_HEADER_LEN,
+        // Audio frames have no type.
+        None => AUDIO_FRAME_HEADER_LEN,
+    }
+}
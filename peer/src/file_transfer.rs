@@ -0,0 +1,394 @@
+//! Peer-to-peer file transfer over a dedicated data channel, framed by `protocol::file`.
+
+use crate::{console_error, console_log};
+use futures_channel::oneshot;
+use js_sys::{Array, ArrayBuffer, Function, Promise, Uint8Array};
+use protocol::file::{Chunk, Frame, Manifest};
+use sha2::{Digest, Sha256};
+use std::{cell::RefCell, collections::BTreeMap};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    Blob, File, HtmlAnchorElement, MessageEvent, RtcDataChannel, RtcDataChannelInit,
+    RtcDataChannelType, RtcPeerConnection, Url,
+};
+
+const FILE_CHANNEL_LABEL: &str = "file";
+
+/// Both peers create the file channel with this id, so it doesn't have to be announced in-band.
+const FILE_CHANNEL_ID: u16 = 1;
+
+/// Size of file chunks, small enough to be sent as one message by every browser.
+const CHUNK_SIZE: u32 = 16 * 1024;
+
+/// Sending pauses once this many bytes are buffered in the channel,
+/// and goes on after the buffered amount drops to `BUFFERED_AMOUNT_LOW_THRESHOLD`.
+const BUFFERED_AMOUNT_HIGH: u32 = 1024 * 1024;
+const BUFFERED_AMOUNT_LOW_THRESHOLD: u32 = 256 * 1024;
+
+/// Largest file sent or received, files are held in memory until they are verified.
+const MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Offers waiting for an answer of the user, further offers are declined.
+const MAX_OFFERS: usize = 8;
+
+thread_local! {
+    static TRANSFERS: RefCell<Transfers> = const {
+        RefCell::new(Transfers {
+            channel: None,
+            answers: BTreeMap::new(),
+            incoming: BTreeMap::new(),
+            offers: BTreeMap::new(),
+            callback: None,
+            offer_callback: None,
+        })
+    };
+}
+
+struct Transfers {
+    channel: Option<RtcDataChannel>,
+    /// Senders waiting for an answer from receiver, by transfer id.
+    answers: BTreeMap<u32, oneshot::Sender<Frame>>,
+    /// Files being received, by SHA-256. Interrupted ones are kept, so that they can be resumed.
+    incoming: BTreeMap<[u8; 32], IncomingFile>,
+    /// Files offered by the other party and not accepted yet, by transfer id.
+    offers: BTreeMap<u32, Manifest>,
+    /// A JS function called with the progress of every transfer.
+    callback: Option<Function>,
+    /// A JS function called with every file offered by the other party.
+    offer_callback: Option<Function>,
+}
+
+struct IncomingFile {
+    manifest: Manifest,
+    data: Vec<u8>,
+    next_chunk: u32,
+}
+
+/// Opens the file channel on a peer connection, it must be done before negotiation.
+pub(crate) fn open(pc: &RtcPeerConnection) {
+    let mut init = RtcDataChannelInit::new();
+    init.negotiated(true).id(FILE_CHANNEL_ID).ordered(true);
+    let channel = pc.create_data_channel_with_data_channel_dict(FILE_CHANNEL_LABEL, &init);
+    channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+    channel.set_buffered_amount_low_threshold(BUFFERED_AMOUNT_LOW_THRESHOLD);
+
+    let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
+        let data = e.data();
+        match data.dyn_into::<ArrayBuffer>() {
+            Ok(buffer) => receive(&Uint8Array::new(&buffer).to_vec()),
+            Err(data) => console_log!("file channel, received Unknown: {:?}", data),
+        }
+    });
+    channel.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+    onmessage_callback.forget();
+
+    TRANSFERS.with(|transfers| transfers.borrow_mut().channel = Some(channel));
+}
+
+/// Sends a file to the other party. Sending the same file again after an interruption resumes the transfer.
+/// Resolves once the other party has verified the file.
+#[wasm_bindgen]
+pub async fn send_file(file: File) -> Result<(), JsValue> {
+    let channel = TRANSFERS
+        .with(|transfers| transfers.borrow().channel.clone())
+        .ok_or("file channel is not open")?;
+
+    if file.size() > MAX_FILE_SIZE as f64 {
+        return Err(format!("file is larger than {} bytes", MAX_FILE_SIZE).into());
+    }
+    let data = Uint8Array::new(&JsFuture::from(file.array_buffer()).await?).to_vec();
+    let manifest = Manifest {
+        transfer_id: transfer_id()?,
+        name: file.name(),
+        size: data.len() as u64,
+        chunk_size: CHUNK_SIZE,
+        sha256: Sha256::digest(&data).into(),
+    };
+
+    let answer = expect_answer(manifest.transfer_id);
+    send_frame(&channel, &Frame::Manifest(manifest.clone()))?;
+    let next_chunk = match answer.await {
+        Ok(Frame::Resume { next_chunk, .. }) => next_chunk,
+        _ => return Err("file was refused".into()),
+    };
+
+    let answer = expect_answer(manifest.transfer_id);
+    for index in next_chunk..manifest.chunk_count() {
+        if channel.buffered_amount() > BUFFERED_AMOUNT_HIGH {
+            buffered_amount_low(&channel).await?;
+        }
+        let range = manifest.chunk_range(index);
+        let transferred = range.end as u64;
+        let chunk = Chunk {
+            transfer_id: manifest.transfer_id,
+            index,
+            data: data[range].to_vec(),
+        };
+        send_frame(&channel, &Frame::Chunk(chunk))?;
+        progress(&manifest, transferred, true);
+    }
+
+    match answer.await {
+        Ok(Frame::Complete { .. }) => Ok(()),
+        _ => Err("file failed the integrity check".into()),
+    }
+}
+
+/// Registers a JS function called with `(name, transferred, size, outgoing)` whenever a transfer progresses,
+/// `transferred` and `size` are in bytes, `outgoing` tells whether the file is sent by this peer.
+#[wasm_bindgen]
+pub fn on_file_progress(callback: Function) {
+    TRANSFERS.with(|transfers| transfers.borrow_mut().callback = Some(callback));
+}
+
+/// Registers a JS function called with `(transfer_id, name, size)` whenever the other party offers a file,
+/// the file is received once it is accepted with `accept_file`. Offers are declined if none is registered.
+#[wasm_bindgen]
+pub fn on_file_offer(callback: Function) {
+    TRANSFERS.with(|transfers| transfers.borrow_mut().offer_callback = Some(callback));
+}
+
+/// Receives a file offered by the other party.
+#[wasm_bindgen]
+pub fn accept_file(transfer_id: u32) -> Result<(), JsValue> {
+    accept(take_offer(transfer_id)?);
+    Ok(())
+}
+
+/// Refuses a file offered by the other party.
+#[wasm_bindgen]
+pub fn decline_file(transfer_id: u32) -> Result<(), JsValue> {
+    take_offer(transfer_id)?;
+    answer(&Frame::Failed { transfer_id });
+    Ok(())
+}
+
+fn take_offer(transfer_id: u32) -> Result<Manifest, JsValue> {
+    TRANSFERS
+        .with(|transfers| transfers.borrow_mut().offers.remove(&transfer_id))
+        .ok_or_else(|| "no such file offer".into())
+}
+
+/// A transfer id, unpredictable so that answers to a transfer can't be forged by guessing it.
+fn transfer_id() -> Result<u32, JsValue> {
+    let mut id = [0; 4];
+    getrandom::getrandom(&mut id).map_err(|err| err.to_string())?;
+    Ok(u32::from_be_bytes(id))
+}
+
+fn expect_answer(transfer_id: u32) -> oneshot::Receiver<Frame> {
+    let (sender, receiver) = oneshot::channel();
+    TRANSFERS.with(|transfers| transfers.borrow_mut().answers.insert(transfer_id, sender));
+    receiver
+}
+
+fn send_frame(channel: &RtcDataChannel, frame: &Frame) -> Result<(), JsValue> {
+    channel.send_with_u8_array(&frame.encode())
+}
+
+/// Answers the sender of a file.
+fn answer(frame: &Frame) {
+    let channel = TRANSFERS.with(|transfers| transfers.borrow().channel.clone());
+    if let Some(channel) = channel {
+        if let Err(err) = send_frame(&channel, frame) {
+            console_error!("could not answer file transfer: {:?}", err);
+        }
+    }
+}
+
+/// Waits for the buffered amount of a channel to drop to its low threshold.
+async fn buffered_amount_low(channel: &RtcDataChannel) -> Result<(), JsValue> {
+    let promise = Promise::new(&mut |resolve, _reject| {
+        channel.set_onbufferedamountlow(Some(&resolve));
+    });
+    JsFuture::from(promise).await?;
+    channel.set_onbufferedamountlow(None);
+    Ok(())
+}
+
+fn receive(bytes: &[u8]) {
+    let frame = match Frame::decode(bytes) {
+        Ok(frame) => frame,
+        Err(err) => {
+            console_error!("invalid file frame: {}", err);
+            return;
+        }
+    };
+    match frame {
+        Frame::Manifest(manifest) => receive_manifest(manifest),
+        Frame::Chunk(chunk) => receive_chunk(chunk),
+        Frame::Resume { transfer_id, .. }
+        | Frame::Complete { transfer_id }
+        | Frame::Failed { transfer_id } => {
+            let answer =
+                TRANSFERS.with(|transfers| transfers.borrow_mut().answers.remove(&transfer_id));
+            if let Some(answer) = answer {
+                answer.send(frame).ok();
+            }
+        }
+    }
+}
+
+fn receive_manifest(manifest: Manifest) {
+    console_log!("offered file {}, {} bytes", manifest.name, manifest.size);
+    let transfer_id = manifest.transfer_id;
+    if manifest.size > MAX_FILE_SIZE || (manifest.size > 0 && manifest.chunk_size == 0) {
+        console_error!("refused file {}, {} bytes", manifest.name, manifest.size);
+        answer(&Frame::Failed { transfer_id });
+        return;
+    }
+
+    // Interrupted transfers were accepted already, they resume without asking again.
+    if TRANSFERS.with(|transfers| transfers.borrow().incoming.contains_key(&manifest.sha256)) {
+        accept(manifest);
+        return;
+    }
+    let callback = TRANSFERS.with(|transfers| {
+        let mut transfers = transfers.borrow_mut();
+        if transfers.offers.len() >= MAX_OFFERS {
+            return None;
+        }
+        let callback = transfers.offer_callback.clone()?;
+        transfers.offers.insert(transfer_id, manifest.clone());
+        Some(callback)
+    });
+    let Some(callback) = callback else {
+        answer(&Frame::Failed { transfer_id });
+        return;
+    };
+    let args = [
+        JsValue::from_f64(transfer_id as f64),
+        JsValue::from_str(&manifest.name),
+        JsValue::from_f64(manifest.size as f64),
+    ];
+    if let Err(err) = callback.apply(&JsValue::NULL, &args.iter().collect()) {
+        console_error!("file offer callback failed: {:?}", err);
+    }
+}
+
+/// Starts or resumes receiving an accepted file.
+fn accept(manifest: Manifest) {
+    console_log!("receiving file {}, {} bytes", manifest.name, manifest.size);
+
+    let next_chunk = TRANSFERS.with(|transfers| {
+        let mut transfers = transfers.borrow_mut();
+        // Data grows as chunks arrive, a manifest alone doesn't allocate its size.
+        let incoming = transfers
+            .incoming
+            .entry(manifest.sha256)
+            .or_insert_with(|| IncomingFile {
+                manifest: manifest.clone(),
+                data: Vec::new(),
+                next_chunk: 0,
+            });
+        // Chunks of an interrupted transfer of the same file are kept, chunks sent from now on carry the new id.
+        if incoming.manifest.chunk_size != manifest.chunk_size {
+            incoming.data.clear();
+            incoming.next_chunk = 0;
+        }
+        incoming.manifest = manifest.clone();
+        incoming.next_chunk
+    });
+
+    answer(&Frame::Resume {
+        transfer_id: manifest.transfer_id,
+        next_chunk,
+    });
+    if next_chunk >= manifest.chunk_count() {
+        finish(manifest.sha256);
+    }
+}
+
+fn receive_chunk(chunk: Chunk) {
+    let received = TRANSFERS.with(|transfers| {
+        let mut transfers = transfers.borrow_mut();
+        let (&sha256, incoming) = transfers
+            .incoming
+            .iter_mut()
+            .find(|(_, incoming)| incoming.manifest.transfer_id == chunk.transfer_id)?;
+        // Chunks arrive in order on a reliable channel, anything else belongs to an outdated transfer.
+        if chunk.index != incoming.next_chunk {
+            return None;
+        }
+        // A chunk which doesn't fit the manifest would grow the file past its size.
+        if !incoming.manifest.is_chunk(chunk.index, chunk.data.len()) {
+            return transfers.incoming.remove(&sha256).map(Err);
+        }
+        incoming.data.extend_from_slice(&chunk.data);
+        incoming.next_chunk += 1;
+        Some(Ok((incoming.manifest.clone(), incoming.data.len() as u64)))
+    });
+    match received {
+        Some(Ok((manifest, transferred))) => {
+            progress(&manifest, transferred, false);
+            if chunk.index + 1 >= manifest.chunk_count() {
+                finish(manifest.sha256);
+            }
+        }
+        Some(Err(IncomingFile { manifest, .. })) => {
+            console_error!(
+                "file {} has an invalid chunk {}",
+                manifest.name,
+                chunk.index
+            );
+            answer(&Frame::Failed {
+                transfer_id: manifest.transfer_id,
+            });
+        }
+        None => {}
+    }
+}
+
+/// Verifies a received file, answers the sender and offers the file for download.
+fn finish(sha256: [u8; 32]) {
+    let incoming = TRANSFERS.with(|transfers| transfers.borrow_mut().incoming.remove(&sha256));
+    let IncomingFile { manifest, data, .. } = match incoming {
+        Some(incoming) => incoming,
+        None => return,
+    };
+
+    let transfer_id = manifest.transfer_id;
+    if <[u8; 32]>::from(Sha256::digest(&data)) != manifest.sha256 {
+        console_error!("file {} failed the integrity check", manifest.name);
+        answer(&Frame::Failed { transfer_id });
+        return;
+    }
+    answer(&Frame::Complete { transfer_id });
+    if let Err(err) = offer_download(&manifest.name, &data) {
+        console_error!("could not offer file for download: {:?}", err);
+    }
+}
+
+fn progress(manifest: &Manifest, transferred: u64, outgoing: bool) {
+    let callback = TRANSFERS.with(|transfers| transfers.borrow().callback.clone());
+    if let Some(callback) = callback {
+        let args = [
+            JsValue::from_str(&manifest.name),
+            JsValue::from_f64(transferred as f64),
+            JsValue::from_f64(manifest.size as f64),
+            JsValue::from_bool(outgoing),
+        ];
+        if let Err(err) = callback.apply(&JsValue::NULL, &args.iter().collect()) {
+            console_error!("file progress callback failed: {:?}", err);
+        }
+    }
+}
+
+/// Adds a download link of a received file to the page.
+fn offer_download(name: &str, data: &[u8]) -> Result<(), JsValue> {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let files = match document.get_element_by_id("files") {
+        Some(files) => files,
+        None => return Ok(()),
+    };
+
+    let parts = Array::of1(&Uint8Array::from(data));
+    let url = Url::create_object_url_with_blob(&Blob::new_with_u8_array_sequence(&parts)?)?;
+    let link: HtmlAnchorElement = document.create_element("a")?.unchecked_into();
+    link.set_href(&url);
+    link.set_download(name);
+    link.set_text_content(Some(name));
+    files.append_child(&link)?;
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use crate::console_error;
+use js_sys::{Array, Object, Reflect};
+use protocol::IceServer;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Response, RtcConfiguration};
+
+/// STUN server used when ICE servers could not be fetched from signal server.
+const FALLBACK_STUN_URL: &str = "stun:stun.l.google.com:19302";
+
+/// Fetches ICE servers, including short-lived TURN credentials, from signal server.
+/// Falls back to a public STUN server if they could not be fetched.
+pub(crate) async fn fetch_ice_servers(url: &str) -> Vec<IceServer> {
+    match try_fetch_ice_servers(url).await {
+        Ok(servers) => servers,
+        Err(err) => {
+            console_error!("could not fetch ICE servers: {:?}", err);
+            vec![IceServer {
+                urls: vec![FALLBACK_STUN_URL.into()],
+                username: None,
+                credential: None,
+            }]
+        }
+    }
+}
+
+async fn try_fetch_ice_servers(url: &str) -> Result<Vec<IceServer>, JsValue> {
+    let response: Response = JsFuture::from(web_sys::window().unwrap().fetch_with_str(url))
+        .await?
+        .dyn_into()?;
+    if !response.ok() {
+        return Err(format!("unexpected status code: {}", response.status()).into());
+    }
+    let text = JsFuture::from(response.text()?).await?;
+    serde_json::from_str(&text.as_string().unwrap_or_default())
+        .map_err(|err| err.to_string().into())
+}
+
+/// Builds a peer connection configuration with ICE servers.
+pub(crate) fn rtc_configuration(servers: &[IceServer]) -> Result<RtcConfiguration, JsValue> {
+    let ice_servers = Array::new();
+    for server in servers {
+        let server_entry = Object::new();
+        let urls: Array = server
+            .urls
+            .iter()
+            .map(|url| JsValue::from_str(url))
+            .collect();
+        Reflect::set(&server_entry, &"urls".into(), &urls)?;
+        if let Some(username) = &server.username {
+            Reflect::set(&server_entry, &"username".into(), &username.into())?;
+        }
+        if let Some(credential) = &server.credential {
+            Reflect::set(&server_entry, &"credential".into(), &credential.into())?;
+        }
+        ice_servers.push(&server_entry);
+    }
+
+    let mut rtc_configuration = RtcConfiguration::new();
+    rtc_configuration.ice_servers(&ice_servers);
+    Ok(rtc_configuration)
+}
@@ -0,0 +1,107 @@
+//! Knock-to-join: a room created with the `knock` query parameter makes the other party knock with its peer info,
+//! and caller admits or denies it before anything is relayed between them.
+
+use crate::{console_error, participant, signal::Signal};
+use js_sys::Function;
+use protocol::{Event, Join, Message, PeerInfo};
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::prelude::*;
+use web_sys::UrlSearchParams;
+
+thread_local! {
+    static KNOCK: RefCell<Knock> = const {
+        RefCell::new(Knock {
+            signal: None,
+            pending: false,
+            callback: None,
+        })
+    };
+}
+
+struct Knock {
+    /// Connection to signal server, answers to knocks are sent through it.
+    signal: Option<Rc<Signal>>,
+    /// Whether a party knocks and waits for an answer.
+    pending: bool,
+    /// A JS function called with the name of every knocking party.
+    callback: Option<Function>,
+}
+
+/// The first message of this peer, joining by passphrase with its peer info and the knock mode of the page url.
+pub(crate) fn join_message(passphrase: &str) -> String {
+    let search = web_sys::window()
+        .unwrap()
+        .location()
+        .search()
+        .unwrap_or_default();
+    let join = Join {
+        passphrase: passphrase.into(),
+        info: participant::local_info(),
+        knock: UrlSearchParams::new_with_str(&search).is_ok_and(|params| params.has("knock")),
+    };
+    let message = Message {
+        event: Event::Join,
+        data: serde_json::to_string(&join).unwrap(),
+    };
+    serde_json::to_string(&message).unwrap()
+}
+
+/// Sets the connection to signal server once it is open.
+pub(crate) fn set_signal(signal: Rc<Signal>) {
+    KNOCK.with(|knock| knock.borrow_mut().signal = Some(signal));
+}
+
+/// Hands a knocking party to the registered callback, it is admitted or denied from the page.
+pub(crate) fn request(data: &str) {
+    let request: PeerInfo = match serde_json::from_str(data) {
+        Ok(request) => request,
+        Err(err) => {
+            console_error!("invalid join request: {}", err);
+            return;
+        }
+    };
+    let callback = KNOCK.with(|knock| {
+        let mut knock = knock.borrow_mut();
+        knock.pending = true;
+        knock.callback.clone()
+    });
+    if let Some(callback) = callback {
+        let name = request.name.map(JsValue::from).unwrap_or(JsValue::NULL);
+        if let Err(err) = callback.call1(&JsValue::NULL, &name) {
+            console_error!("join request callback failed: {:?}", err);
+        }
+    }
+}
+
+/// Registers a JS function called with the name of a party knocking on the room, or null if it has none.
+#[wasm_bindgen]
+pub fn on_join_request(callback: Function) {
+    KNOCK.with(|knock| knock.borrow_mut().callback = Some(callback));
+}
+
+/// Lets the knocking party in.
+#[wasm_bindgen]
+pub fn admit() -> Result<(), JsValue> {
+    answer(Event::Admit)
+}
+
+/// Turns the knocking party away.
+#[wasm_bindgen]
+pub fn deny() -> Result<(), JsValue> {
+    answer(Event::Deny)
+}
+
+fn answer(event: Event) -> Result<(), JsValue> {
+    let signal = KNOCK.with(|knock| {
+        let mut knock = knock.borrow_mut();
+        if !knock.pending {
+            return Err("nobody is knocking");
+        }
+        knock.pending = false;
+        knock.signal.clone().ok_or("not connected to signal server")
+    })?;
+    signal.send(&Message {
+        event,
+        data: String::new(),
+    })
+}
@@ -1,15 +1,42 @@
+pub use chat::{on_chat, send_chat};
+pub use file_transfer::{on_file_progress, send_file};
+pub use knock::{admit, deny, on_join_request};
 use session::Session;
+pub use verification::{mark_verified, on_sas};
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
+mod candidates;
+mod chat;
+mod e2ee;
+mod encoded_transform;
+mod file_transfer;
+mod ice;
+mod knock;
+mod pake;
+mod participant;
 mod pc_callbacks;
+mod peer_connection;
+mod room;
+mod sas;
 mod session;
+mod signal;
+mod state;
 mod utils;
+mod verification;
 mod ws_callbacks;
 
 #[wasm_bindgen(start)]
 pub async fn main() -> Result<(), JsValue> {
     utils::set_panic_hook();
 
-    let session = Session::new("ws://localhost:8787/signal".into());
-    session.start().await
+    let session = Session::new(
+        "ws://localhost:8787/signal".into(),
+        "http://localhost:8787/ice-servers".into(),
+        "http://localhost:8787/rooms".into(),
+    );
+    let started = session.start().await;
+    if let Err(err) = &started {
+        utils::show_error(&format!("could not start the call: {:?}", err));
+    }
+    started
 }
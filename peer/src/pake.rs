@@ -0,0 +1,273 @@
+//! Password authenticated key exchange between the peers of a call, and encryption of signaling payloads
+//! with the exchanged key, so that signal server and its store relay only ciphertext.
+//!
+//! The exchange follows CPace over ristretto255, in its initiator-responder setting. Caller picks a random
+//! session id, hashes it along with the passphrase and the room id to a generator `g`, and sends the session id
+//! and `g^x` for a random secret `x` as its `KeyShare`. Callee derives the same generator and answers with `g^y`.
+//! Only peers knowing the passphrase agree on the shared secret `g^xy`, and nobody merely relaying the shares
+//! can test passphrase guesses against them offline. `Offer`, `Answer` and `IceCandidate` payloads are
+//! then sealed with AES-256-GCM under a key derived from the shared secret, the session id and both shares,
+//! and so are media frames with end-to-end encryption, under another key derived the same way.
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use curve25519_dalek::{
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    traits::IsIdentity,
+    Scalar,
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+
+const GENERATOR_DOMAIN: &[u8] = b"hangout-cpace-ristretto255";
+const KEY_INFO: &[u8] = b"hangout-signal-key";
+const MEDIA_KEY_INFO: &[u8] = b"hangout-media-key";
+
+const NONCE_LEN: usize = 12;
+
+/// Length of session ids in bytes.
+const SESSION_ID_LEN: usize = 16;
+
+/// Length of an encoded share in bytes.
+const SHARE_LEN: usize = 32;
+
+/// Caller's side of a key exchange, from sending its share until receiving callee's share.
+pub(crate) struct KeyExchange {
+    session_id: [u8; SESSION_ID_LEN],
+    secret: Scalar,
+    share: CompressedRistretto,
+}
+
+/// Seals and opens signaling payloads with an exchanged key.
+pub(crate) struct SignalCipher {
+    cipher: Aes256Gcm,
+    /// Base key of end-to-end media encryption.
+    media_key: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PakeError {
+    /// The other party's share is not an encoded point, or a point no honest party sends.
+    InvalidShare,
+    /// A payload is not sealed with the same key, the other party likely used another passphrase.
+    Decryption,
+}
+
+impl KeyExchange {
+    /// Starts an exchange as caller, with a random session id and secret.
+    pub(crate) fn initiate(passphrase: &str, room_id: &str) -> KeyExchange {
+        let mut session_id = [0; SESSION_ID_LEN];
+        getrandom::getrandom(&mut session_id).expect("could not generate random bytes");
+        let secret = random_scalar();
+        let share = (generator(passphrase, room_id, &session_id) * secret).compress();
+        KeyExchange {
+            session_id,
+            secret,
+            share,
+        }
+    }
+
+    /// The share to send to callee, the session id followed by the point.
+    pub(crate) fn share(&self) -> String {
+        STANDARD.encode([&self.session_id[..], self.share.as_bytes()].concat())
+    }
+
+    /// Completes the exchange with callee's share.
+    pub(crate) fn finish(self, callee_share: &str) -> Result<SignalCipher, PakeError> {
+        let callee_share = STANDARD
+            .decode(callee_share)
+            .map_err(|_| PakeError::InvalidShare)?;
+        let (callee_share, callee_point) = decode_share(&callee_share, &self.share)?;
+        SignalCipher::new(
+            &self.session_id,
+            callee_point * self.secret,
+            &self.share,
+            &callee_share,
+        )
+    }
+}
+
+/// Answers caller's share as callee. Returns the share to send back and the cipher of the exchanged key.
+pub(crate) fn respond(
+    passphrase: &str,
+    room_id: &str,
+    caller_share: &str,
+) -> Result<(String, SignalCipher), PakeError> {
+    let caller_share = STANDARD
+        .decode(caller_share)
+        .map_err(|_| PakeError::InvalidShare)?;
+    if caller_share.len() != SESSION_ID_LEN + SHARE_LEN {
+        return Err(PakeError::InvalidShare);
+    }
+    let (session_id, caller_share) = caller_share.split_at(SESSION_ID_LEN);
+
+    let secret = random_scalar();
+    let share = (generator(passphrase, room_id, session_id) * secret).compress();
+    let (caller_share, caller_point) = decode_share(caller_share, &share)?;
+    let cipher = SignalCipher::new(session_id, caller_point * secret, &caller_share, &share)?;
+    Ok((STANDARD.encode(share.as_bytes()), cipher))
+}
+
+impl SignalCipher {
+    /// Derives the keys of an exchange, bound to the session id and both shares, caller's first.
+    fn new(
+        session_id: &[u8],
+        shared_secret: RistrettoPoint,
+        caller_share: &CompressedRistretto,
+        callee_share: &CompressedRistretto,
+    ) -> Result<SignalCipher, PakeError> {
+        if shared_secret.is_identity() {
+            return Err(PakeError::InvalidShare);
+        }
+        let hkdf = Hkdf::<Sha256>::new(Some(session_id), shared_secret.compress().as_bytes());
+        let [key, media_key] = [KEY_INFO, MEDIA_KEY_INFO].map(|info| {
+            let mut key = [0; 32];
+            hkdf.expand_multi_info(
+                &[info, caller_share.as_bytes(), callee_share.as_bytes()],
+                &mut key,
+            )
+            .expect("32 bytes is a valid HKDF output length");
+            key
+        });
+        Ok(SignalCipher {
+            cipher: Aes256Gcm::new(&key.into()),
+            media_key,
+        })
+    }
+
+    /// Base key of end-to-end media encryption, both peers of an exchange get the same one.
+    pub(crate) fn media_key(&self) -> [u8; 32] {
+        self.media_key
+    }
+
+    /// Seals a payload into base64 of a random nonce followed by the ciphertext.
+    pub(crate) fn seal(&self, payload: &str) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).expect("could not generate random bytes");
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload.as_bytes())
+            .expect("encryption of a payload never fails");
+        STANDARD.encode([&nonce[..], &ciphertext].concat())
+    }
+
+    /// Opens a payload sealed by the other party.
+    pub(crate) fn open(&self, sealed: &str) -> Result<String, PakeError> {
+        let sealed = STANDARD.decode(sealed).map_err(|_| PakeError::Decryption)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(PakeError::Decryption);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| PakeError::Decryption)?;
+        String::from_utf8(payload).map_err(|_| PakeError::Decryption)
+    }
+}
+
+impl fmt::Display for PakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PakeError::InvalidShare => write!(f, "invalid key share"),
+            PakeError::Decryption => write!(f, "payload could not be decrypted"),
+        }
+    }
+}
+
+/// Hashes a passphrase, the room id and the session id to a generator of ristretto255.
+fn generator(passphrase: &str, room_id: &str, session_id: &[u8]) -> RistrettoPoint {
+    let mut hash = Sha512::new();
+    // Length prefixes keep the inputs apart, no other combination of them hashes the same.
+    for input in [
+        GENERATOR_DOMAIN,
+        passphrase.as_bytes(),
+        room_id.as_bytes(),
+        session_id,
+    ] {
+        hash.update((input.len() as u64).to_be_bytes());
+        hash.update(input);
+    }
+    RistrettoPoint::from_uniform_bytes(&hash.finalize().into())
+}
+
+fn random_scalar() -> Scalar {
+    let mut bytes = [0; 64];
+    getrandom::getrandom(&mut bytes).expect("could not generate random bytes");
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+/// Decodes the other party's share, refusing the identity and a share sent back to its owner.
+fn decode_share(
+    bytes: &[u8],
+    own_share: &CompressedRistretto,
+) -> Result<(CompressedRistretto, RistrettoPoint), PakeError> {
+    let share = CompressedRistretto::from_slice(bytes).map_err(|_| PakeError::InvalidShare)?;
+    match share.decompress() {
+        Some(point) if !point.is_identity() && share != *own_share => Ok((share, point)),
+        _ => Err(PakeError::InvalidShare),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{respond, KeyExchange, PakeError, SESSION_ID_LEN};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    const ROOM_ID: &str = "0123456789abcdef";
+
+    #[test]
+    fn same_passphrase() {
+        let caller = KeyExchange::initiate("passphrase", ROOM_ID);
+        let (callee_share, callee) = respond("passphrase", ROOM_ID, &caller.share()).unwrap();
+        let caller = caller.finish(&callee_share).unwrap();
+
+        let sealed = caller.seal("v=0 offer");
+        assert!(!sealed.contains("offer"));
+        assert_eq!(callee.open(&sealed).unwrap(), "v=0 offer");
+        assert_eq!(caller.media_key(), callee.media_key());
+    }
+
+    #[test]
+    fn different_passphrases_or_rooms() {
+        for (passphrase, room_id) in [("guess", ROOM_ID), ("passphrase", "fedcba9876543210")] {
+            let caller = KeyExchange::initiate("passphrase", ROOM_ID);
+            let (callee_share, callee) = respond(passphrase, room_id, &caller.share()).unwrap();
+            let caller = caller.finish(&callee_share).unwrap();
+
+            assert_eq!(
+                callee.open(&caller.seal("v=0 offer")),
+                Err(PakeError::Decryption)
+            );
+            assert_ne!(caller.media_key(), callee.media_key());
+        }
+    }
+
+    #[test]
+    fn invalid_shares() {
+        let identity = STANDARD.encode([0; 32]);
+        let not_a_point = STANDARD.encode([0xff; 32]);
+        for share in [identity.as_str(), not_a_point.as_str(), "not base64", ""] {
+            let exchange = KeyExchange::initiate("passphrase", ROOM_ID);
+            assert_eq!(exchange.finish(share).err(), Some(PakeError::InvalidShare));
+
+            let with_session_id = STANDARD.encode(
+                [
+                    &[7; SESSION_ID_LEN][..],
+                    &STANDARD.decode(share).unwrap_or_default(),
+                ]
+                .concat(),
+            );
+            assert_eq!(
+                respond("passphrase", ROOM_ID, &with_session_id).err(),
+                Some(PakeError::InvalidShare)
+            );
+        }
+
+        // A share sent back to its owner is refused.
+        let exchange = KeyExchange::initiate("passphrase", ROOM_ID);
+        let share = STANDARD.encode(&STANDARD.decode(exchange.share()).unwrap()[SESSION_ID_LEN..]);
+        assert_eq!(exchange.finish(&share).err(), Some(PakeError::InvalidShare));
+    }
+}
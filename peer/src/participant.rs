@@ -0,0 +1,64 @@
+//! What this peer tells the other party about itself, and the label of the remote video.
+//!
+//! The name and avatar color are taken from the `name` and `color` query parameters of the page url,
+//! a peer without a color picks one at random.
+
+use crate::console_error;
+use protocol::PeerInfo;
+use wasm_bindgen::JsCast;
+use web_sys::{HtmlElement, UrlSearchParams};
+
+/// Avatar colors picked from when the page url has none.
+const COLORS: [&str; 8] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#469990",
+];
+
+/// Describes this peer from the page url.
+pub(crate) fn local_info() -> PeerInfo {
+    let search = web_sys::window()
+        .unwrap()
+        .location()
+        .search()
+        .unwrap_or_default();
+    let params = UrlSearchParams::new_with_str(&search).ok();
+    let param = |name: &str| {
+        params
+            .as_ref()
+            .and_then(|params| params.get(name))
+            .filter(|value| !value.is_empty())
+    };
+    let color = param("color")
+        .map(|color| format!("#{}", color.trim_start_matches('#')))
+        .unwrap_or_else(|| COLORS[(js_sys::Math::random() * COLORS.len() as f64) as usize].into());
+    PeerInfo {
+        name: param("name"),
+        color: Some(color),
+        client: Some(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).into()),
+    }
+}
+
+/// Labels the remote video with the name and color of the other party.
+pub(crate) fn show_remote(data: &str) {
+    let info: PeerInfo = match serde_json::from_str(data) {
+        Ok(info) => info,
+        Err(err) => {
+            console_error!("invalid peer info: {}", err);
+            return;
+        }
+    };
+    let document = web_sys::window().unwrap().document().unwrap();
+    let Some(label) = document
+        .get_element_by_id("remoteLabel")
+        .and_then(|element| element.dyn_into::<HtmlElement>().ok())
+    else {
+        return;
+    };
+    label.set_text_content(Some(info.name.as_deref().unwrap_or("Remote")));
+    // Signal server only relays colors in the form of `#rrggbb`.
+    if let Some(color) = &info.color {
+        label.style().set_property("color", color).ok();
+    }
+    if let Some(client) = &info.client {
+        label.set_title(client);
+    }
+}
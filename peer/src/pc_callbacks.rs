@@ -1,41 +1,57 @@
-use crate::console_log;
+use crate::{console_error, console_log, encoded_transform::EncodedTransform, signal::Signal};
+use futures_channel::mpsc::UnboundedSender;
 use protocol::{Event, Message};
+use std::rc::Rc;
 use wasm_bindgen::{prelude::Closure, JsCast};
 use web_sys::{
-    HtmlMediaElement, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcTrackEvent, WebSocket,
+    HtmlMediaElement, RtcIceConnectionState, RtcPeerConnection, RtcPeerConnectionIceEvent,
+    RtcTrackEvent,
 };
 
-pub(crate) fn set_onicecandidate(pc: &RtcPeerConnection, ws: WebSocket) {
+pub(crate) fn set_onicecandidate(pc: &RtcPeerConnection, signal: Rc<Signal>) {
     let onicecandidate_callback =
-        Closure::<dyn FnMut(_)>::new(move |ev: RtcPeerConnectionIceEvent| match ev.candidate() {
-            Some(candidate) => {
+        Closure::<dyn FnMut(_)>::new(move |ev: RtcPeerConnectionIceEvent| {
+            if let Some(candidate) = ev.candidate() {
                 console_log!("pc.onicecandidate: {}", candidate.candidate());
                 let message = Message {
                     event: Event::IceCandidate,
                     data: candidate.candidate(),
                 };
-                ws.send_with_str(&serde_json::to_string(&message).unwrap())
-                    .unwrap();
-                console_log!("successfully sent a candidate");
+                match signal.send(&message) {
+                    Ok(()) => console_log!("successfully sent a candidate"),
+                    Err(err) => console_error!("could not send a candidate: {:?}", err),
+                }
             }
-            None => {}
         });
     pc.set_onicecandidate(Some(onicecandidate_callback.as_ref().unchecked_ref()));
     onicecandidate_callback.forget();
 }
 
-pub(crate) fn set_onconnectionstatechange(pc: &RtcPeerConnection) {
+/// Forwards ICE connection state changes to sender.
+pub(crate) fn set_onconnectionstatechange(
+    pc: &RtcPeerConnection,
+    sender: UnboundedSender<RtcIceConnectionState>,
+) {
     let pc_clone = pc.clone();
     let onconnectionstatechange_callback = Closure::<dyn FnMut()>::new(move || {
-        console_log!("pc state: {:?}", pc_clone.ice_connection_state());
+        sender
+            .unbounded_send(pc_clone.ice_connection_state())
+            .unwrap();
     });
     pc.set_oniceconnectionstatechange(Some(
         onconnectionstatechange_callback.as_ref().unchecked_ref(),
     ));
+    onconnectionstatechange_callback.forget();
 }
 
-pub(crate) fn set_ontrack(pc: &RtcPeerConnection) {
+/// Plays remote tracks, decrypting them first if media is end-to-end encrypted.
+pub(crate) fn set_ontrack(pc: &RtcPeerConnection, e2ee: Option<EncodedTransform>) {
     let ontrack_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcTrackEvent| {
+        if let Some(e2ee) = &e2ee {
+            if let Err(err) = e2ee.attach_receiver(&ev.receiver()) {
+                console_error!("could not decrypt a remote track: {:?}", err);
+            }
+        }
         let first_remote_stream = ev.streams().pop();
         web_sys::window()
             .unwrap()
@@ -47,4 +63,5 @@ pub(crate) fn set_ontrack(pc: &RtcPeerConnection) {
             .set_src_object(first_remote_stream.dyn_ref());
     });
     pc.set_ontrack(Some(ontrack_callback.as_ref().unchecked_ref()));
+    ontrack_callback.forget();
 }
@@ -0,0 +1,45 @@
+//! Peer connection operations driven by signaling, abstracted from `RtcPeerConnection` so that
+//! signaling logic can be tested without a browser.
+
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    RtcIceCandidate, RtcIceCandidateInit, RtcPeerConnection, RtcSdpType, RtcSessionDescriptionInit,
+};
+
+pub(crate) trait PeerConnection {
+    type Error;
+
+    /// Applies a session description received from the other party.
+    async fn set_remote_description(
+        &self,
+        sdp_type: RtcSdpType,
+        sdp: &str,
+    ) -> Result<(), Self::Error>;
+
+    /// Adds an ICE candidate received from the other party.
+    /// It fails if the remote description is not set yet.
+    async fn add_ice_candidate(&self, candidate: &str) -> Result<(), Self::Error>;
+}
+
+impl PeerConnection for RtcPeerConnection {
+    type Error = JsValue;
+
+    async fn set_remote_description(&self, sdp_type: RtcSdpType, sdp: &str) -> Result<(), JsValue> {
+        let mut description = RtcSessionDescriptionInit::new(sdp_type);
+        description.sdp(sdp);
+        JsFuture::from(RtcPeerConnection::set_remote_description(
+            self,
+            &description,
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn add_ice_candidate(&self, candidate: &str) -> Result<(), JsValue> {
+        let candidate = RtcIceCandidate::new(&RtcIceCandidateInit::new(candidate))?;
+        let promise = self.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&candidate));
+        JsFuture::from(promise).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! The passphrase of a call, taken from the fragment of the page url, which browsers never send to servers.
+//! Without one, a room code generated by signal server is used and put in the url to be shared.
+//!
+//! When signal server requires authentication, the page url carries a token in its `token` query parameter,
+//! and rooms created with it come with an invite link for the other party.
+
+use js_sys::{decode_uri_component, encode_uri_component, Object, Reflect};
+use protocol::RoomCode;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{RequestInit, Response, UrlSearchParams};
+
+/// Returns the token to authenticate with from the page url, if any.
+pub(crate) fn token() -> Option<String> {
+    let search = web_sys::window().unwrap().location().search().ok()?;
+    UrlSearchParams::new_with_str(&search)
+        .ok()?
+        .get("token")
+        .filter(|token| !token.is_empty())
+}
+
+/// Adds a token to the url of signal server, browsers can't set headers of WebSocket requests.
+pub(crate) fn authenticated_url(url: &str, token: Option<&str>) -> String {
+    match token {
+        Some(token) => format!("{}?token={}", url, encode_uri_component(token)),
+        None => url.into(),
+    }
+}
+
+/// Returns the passphrase of the call this page is for, creating a room if there is none.
+pub(crate) async fn passphrase(rooms_url: &str, token: Option<&str>) -> Result<String, JsValue> {
+    let location = web_sys::window().unwrap().location();
+    let fragment = location.hash()?;
+    let passphrase = decode_uri_component(fragment.trim_start_matches('#'))?
+        .as_string()
+        .unwrap_or_default();
+    if !passphrase.is_empty() {
+        show(&passphrase);
+        return Ok(passphrase);
+    }
+
+    let room = create_room(rooms_url, token).await?;
+    location.set_hash(&room.code)?;
+    show(&room.code);
+    if let Some(invite) = &room.invite {
+        show_invite(&room.code, invite)?;
+    }
+    Ok(room.code)
+}
+
+async fn create_room(url: &str, token: Option<&str>) -> Result<RoomCode, JsValue> {
+    let mut request_init = RequestInit::new();
+    request_init.method("POST");
+    if let Some(token) = token {
+        let headers = Object::new();
+        Reflect::set(
+            &headers,
+            &"Authorization".into(),
+            &format!("Bearer {}", token).into(),
+        )?;
+        request_init.headers(&headers);
+    }
+    let response: Response = JsFuture::from(
+        web_sys::window()
+            .unwrap()
+            .fetch_with_str_and_init(url, &request_init),
+    )
+    .await?
+    .dyn_into()?;
+    if !response.ok() {
+        return Err(format!("unexpected status code: {}", response.status()).into());
+    }
+    let text = JsFuture::from(response.text()?).await?;
+    serde_json::from_str(&text.as_string().unwrap_or_default())
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Shows the passphrase in the page, for the user to share it with the other party.
+fn show(passphrase: &str) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    if let Some(element) = document.get_element_by_id("roomCode") {
+        element.set_text_content(Some(passphrase));
+    }
+}
+
+/// Shows a link to the room carrying an invite, which lets the other party join this room only.
+fn show_invite(code: &str, invite: &str) -> Result<(), JsValue> {
+    let location = web_sys::window().unwrap().location();
+    let params = UrlSearchParams::new_with_str(&location.search()?)?;
+    params.set("token", invite);
+    let link = format!(
+        "{}{}?{}#{}",
+        location.origin()?,
+        location.pathname()?,
+        String::from(params.to_string()),
+        code
+    );
+    let document = web_sys::window().unwrap().document().unwrap();
+    if let Some(element) = document.get_element_by_id("inviteLink") {
+        element.set_text_content(Some(&link));
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! Short authentication strings, derived from the DTLS fingerprints of both peers, for out-of-band comparison.
+//!
+//! Signal server relays the session descriptions carrying DTLS fingerprints, so a compromised server could
+//! swap them and sit in the middle of the media. Both peers derive the same string from the two fingerprints
+//! they negotiated; if the strings read out by the parties differ, somebody is in the middle.
+
+use sha2::{Digest, Sha256};
+
+const SAS_DOMAIN: &[u8] = b"hangout-sas";
+
+/// Number of symbols of a short authentication string, 6 bits each.
+const SAS_LEN: usize = 7;
+
+/// Symbols of short authentication strings, as emoji and words.
+const SYMBOLS: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐎", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("☁️", "cloud"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("❤️", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("☂️", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("✏️", "pencil"),
+    ("📎", "paperclip"),
+    ("✂️", "scissors"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("☎️", "telephone"),
+    ("🏁", "flag"),
+    ("🚂", "train"),
+    ("🚲", "bicycle"),
+    ("✈️", "aeroplane"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+/// A short authentication string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Sas {
+    symbols: [usize; SAS_LEN],
+}
+
+/// Extracts the DTLS fingerprint of a session description, as `<hash function> <hex digest>`.
+pub(crate) fn fingerprint(sdp: &str) -> Option<String> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .map(|fingerprint| fingerprint.trim().to_ascii_uppercase())
+}
+
+impl Sas {
+    /// Derives the string of a call from the fingerprints of both peers, in either order.
+    pub(crate) fn new(local_fingerprint: &str, remote_fingerprint: &str) -> Sas {
+        let (first, second) = if local_fingerprint <= remote_fingerprint {
+            (local_fingerprint, remote_fingerprint)
+        } else {
+            (remote_fingerprint, local_fingerprint)
+        };
+        let digest = Sha256::new()
+            .chain_update(SAS_DOMAIN)
+            .chain_update((first.len() as u32).to_be_bytes())
+            .chain_update(first)
+            .chain_update(second)
+            .finalize();
+
+        let bits = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        let mut symbols = [0; SAS_LEN];
+        for (i, symbol) in symbols.iter_mut().enumerate() {
+            *symbol = ((bits >> (64 - 6 * (i + 1))) & 0x3f) as usize;
+        }
+        Sas { symbols }
+    }
+
+    pub(crate) fn emoji(&self) -> String {
+        self.symbols
+            .iter()
+            .map(|&symbol| SYMBOLS[symbol].0)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub(crate) fn words(&self) -> String {
+        self.symbols
+            .iter()
+            .map(|&symbol| SYMBOLS[symbol].1)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint, Sas};
+
+    const SDP: &str = "v=0\r\n\
+        o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+        a=fingerprint:sha-256 19:E2:1C:3B:4B:9F:81:E6:b8:5C:F4:A5:A8:D8:73:04\r\n\
+        a=setup:actpass\r\n";
+
+    #[test]
+    fn parse_fingerprint() {
+        assert_eq!(
+            fingerprint(SDP).as_deref(),
+            Some("SHA-256 19:E2:1C:3B:4B:9F:81:E6:B8:5C:F4:A5:A8:D8:73:04")
+        );
+        assert_eq!(fingerprint("v=0\r\n"), None);
+    }
+
+    #[test]
+    fn same_on_both_ends() {
+        let caller = Sas::new("SHA-256 AA:BB", "SHA-256 CC:DD");
+        let callee = Sas::new("SHA-256 CC:DD", "SHA-256 AA:BB");
+        assert_eq!(caller, callee);
+        assert_eq!(caller.emoji().split(' ').count(), 7);
+        assert_eq!(caller.words().split(", ").count(), 7);
+
+        // A swapped fingerprint changes the string.
+        assert_ne!(Sas::new("SHA-256 AA:BB", "SHA-256 CC:DE"), caller);
+    }
+}
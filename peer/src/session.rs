@@ -1,70 +1,137 @@
-use crate::{console_log, pc_callbacks, ws_callbacks};
+use crate::{
+    candidates::PendingCandidates,
+    chat, console_error, console_log,
+    encoded_transform::{self, EncodedTransform},
+    file_transfer, ice, knock,
+    pake::{self, KeyExchange},
+    participant, pc_callbacks, room,
+    signal::Signal,
+    state::{Input, State},
+    utils, verification,
+};
+use futures::{stream, StreamExt};
 use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use js_sys::{Array, Object, Reflect};
-use protocol::{Event, Message};
+use js_sys::Reflect;
+use protocol::{Event, Message, Role, ServerError};
+use std::rc::Rc;
 use wasm_bindgen::{prelude::*, JsCast};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    HtmlVideoElement, MediaStream, MediaStreamConstraints, RtcConfiguration, RtcIceCandidate,
-    RtcIceCandidateInit, RtcPeerConnection, RtcSdpType, RtcSessionDescriptionInit, WebSocket,
+    HtmlVideoElement, MediaStream, MediaStreamConstraints, RtcIceConnectionState,
+    RtcPeerConnection, RtcSdpType, RtcSessionDescriptionInit,
 };
 
 pub(crate) struct Session {
     ws_addr: String,
+    ice_servers_url: String,
+    rooms_url: String,
     sender: UnboundedSender<Message>,
     receiver: UnboundedReceiver<Message>,
 }
 
+/// Anything a peer reacts to during a call.
+#[derive(Debug)]
+enum Incoming {
+    Message(Message),
+    IceState(RtcIceConnectionState),
+}
+
+/// Progress of the signaling key exchange, which starts once signal server tells the room and the role.
+#[derive(Default)]
+struct KeyAgreement {
+    room_id: Option<String>,
+    role: Option<Role>,
+    /// Caller's exchange, until callee answers it.
+    exchange: Option<KeyExchange>,
+    /// Media encryption, keyed by every completed exchange.
+    e2ee: Option<EncodedTransform>,
+}
+
 impl Session {
-    pub(crate) fn new(ws_addr: String) -> Session {
+    pub(crate) fn new(ws_addr: String, ice_servers_url: String, rooms_url: String) -> Session {
         let (sender, receiver) = mpsc::unbounded();
         Session {
             ws_addr,
+            ice_servers_url,
+            rooms_url,
             sender,
             receiver,
         }
     }
 
     pub(crate) async fn start(self) -> Result<(), JsValue> {
-        let ws = WebSocket::new(&self.ws_addr)?;
-        ws_callbacks::set_onopen(&ws, "passphrase".into());
-        ws_callbacks::set_onerror(&ws);
-        ws_callbacks::set_onclose(&ws);
-
-        let pc = RtcPeerConnection::new_with_configuration(&{
-            let ice_servers = Array::new();
-            let server_entry = Object::new();
-            Reflect::set(
-                &server_entry,
-                &"urls".into(),
-                &"stun:stun.l.google.com:19302".into(),
-            )?;
-            ice_servers.push(&server_entry);
-
-            let mut rtc_configuration = RtcConfiguration::new();
-            rtc_configuration.ice_servers(&ice_servers);
-            rtc_configuration
-        })?;
+        let token = room::token();
+        let passphrase = room::passphrase(&self.rooms_url, token.as_deref()).await?;
+        let e2ee = Self::init_e2ee()?;
+
+        let ice_servers_url = room::authenticated_url(&self.ice_servers_url, token.as_deref());
+        let ice_servers = ice::fetch_ice_servers(&ice_servers_url).await;
+        let rtc_configuration = ice::rtc_configuration(&ice_servers)?;
+        if e2ee.is_some() {
+            encoded_transform::configure(&rtc_configuration)?;
+        }
+        let pc = RtcPeerConnection::new_with_configuration(&rtc_configuration)?;
         console_log!("created pc");
 
-        Self::init_local_stream(&pc).await.unwrap();
+        Self::init_local_stream(&pc, e2ee.as_ref()).await?;
+
+        chat::open(&pc);
+        file_transfer::open(&pc);
+        pc_callbacks::set_ontrack(&pc, e2ee.clone());
+        let (ice_state_sender, ice_state_receiver) = mpsc::unbounded();
+        pc_callbacks::set_onconnectionstatechange(&pc, ice_state_sender);
 
-        pc_callbacks::set_ontrack(&pc);
-        pc_callbacks::set_onconnectionstatechange(&pc);
-        pc_callbacks::set_onicecandidate(&pc, ws.clone());
+        let ws_addr = room::authenticated_url(&self.ws_addr, token.as_deref());
+        let signal = Signal::connect(ws_addr, knock::join_message(&passphrase), self.sender)?;
+        knock::set_signal(signal.clone());
+        pc_callbacks::set_onicecandidate(&pc, signal.clone());
 
         wasm_bindgen_futures::spawn_local(Self::handle_message(
+            passphrase,
             self.receiver,
-            ws.clone(),
+            ice_state_receiver,
+            signal,
             pc.clone(),
+            e2ee,
         ));
 
-        ws_callbacks::set_onmessage(&ws, self.sender);
-
         Ok(())
     }
 
-    async fn init_local_stream(pc: &RtcPeerConnection) -> Result<(), JsValue> {
+    /// Sets up media encryption if it is asked for and supported.
+    fn init_e2ee() -> Result<Option<EncodedTransform>, JsValue> {
+        if !encoded_transform::requested() {
+            encoded_transform::set_indicator("not end-to-end encrypted");
+            return Ok(None);
+        }
+        if !encoded_transform::supported() {
+            console_error!(
+                "insertable streams are not supported, media is not end-to-end encrypted"
+            );
+            encoded_transform::set_indicator(
+                "not end-to-end encrypted, unsupported by this browser",
+            );
+            return Ok(None);
+        }
+        match EncodedTransform::new() {
+            Ok(e2ee) => {
+                encoded_transform::set_indicator("end-to-end encrypted");
+                Ok(Some(e2ee))
+            }
+            Err(err) => {
+                console_error!("could not set up media encryption: {:?}", err);
+                encoded_transform::set_indicator(
+                    "not end-to-end encrypted, it could not be set up",
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    async fn init_local_stream(
+        pc: &RtcPeerConnection,
+        e2ee: Option<&EncodedTransform>,
+    ) -> Result<(), JsValue> {
         let local_stream = {
             let promise = web_sys::window()
                 .unwrap()
@@ -93,7 +160,15 @@ impl Session {
         local_stream
             .get_tracks()
             .for_each(&mut |track: JsValue, _, _| {
-                pc.add_track_0(track.dyn_ref().unwrap(), &local_stream);
+                let sender = pc.add_track_0(track.dyn_ref().unwrap(), &local_stream);
+                if let Some(e2ee) = e2ee {
+                    if let Err(err) = e2ee.attach_sender(&sender) {
+                        console_error!("could not encrypt a local track: {:?}", err);
+                        encoded_transform::set_indicator(
+                            "not end-to-end encrypted, local media could not be encrypted",
+                        );
+                    }
+                }
                 console_log!("added a local track");
             });
 
@@ -103,66 +178,239 @@ impl Session {
     }
 
     async fn handle_message(
-        mut receiver: UnboundedReceiver<Message>,
-        ws: WebSocket,
+        passphrase: String,
+        receiver: UnboundedReceiver<Message>,
+        ice_states: UnboundedReceiver<RtcIceConnectionState>,
+        signal: Rc<Signal>,
         pc: RtcPeerConnection,
+        e2ee: Option<EncodedTransform>,
     ) {
-        loop {
-            if let Ok(Some(message)) = receiver.try_next() {
-                match message.event {
-                    Event::Passphrase => {
-                        // If peer's role is caller, send its offer to callee.
-                        if message.data.eq("1") {
-                            console_log!("this is a caller");
-
-                            Self::send_offer(&ws, &pc).await.unwrap();
-                            console_log!("caller sent offer");
-                        }
-                    }
-                    Event::Offer => {
-                        // Callee receives offer from caller.
-                        console_log!("callee received offer");
+        let mut state = State::Idle
+            .transition(Input::Join)
+            .expect("an idle peer can always join");
+        let mut candidates = PendingCandidates::default();
+        let mut agreement = KeyAgreement {
+            e2ee,
+            ..KeyAgreement::default()
+        };
 
-                        let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-                        offer_obj.sdp(&message.data);
-                        let srd_promise = pc.set_remote_description(&offer_obj);
-                        JsFuture::from(srd_promise).await.unwrap();
-                        console_log!("pc: state {:?}", pc.signaling_state());
+        let mut incoming = stream::select(
+            receiver.map(Incoming::Message),
+            ice_states.map(Incoming::IceState),
+        );
+        // Wait for the next message or ICE connection state change, the loop ends when both channels are closed.
+        while let Some(incoming) = incoming.next().await {
+            let input = match &incoming {
+                Incoming::Message(message) => Input::from(&message.event),
+                Incoming::IceState(ice_state) => {
+                    console_log!("pc state: {:?}", ice_state);
+                    match Input::from_ice_connection_state(*ice_state) {
+                        Some(input) => input,
+                        None => continue,
+                    }
+                }
+            };
+            state = match state.transition(input) {
+                Ok(next) => next,
+                Err(err) => {
+                    console_error!("ignored {:?}: {}", incoming, err);
+                    continue;
+                }
+            };
 
-                        // Callee returns answer to caller.
-                        Self::send_answer(&ws, &pc).await.unwrap();
+            if let Incoming::Message(message) = incoming {
+                match signal.open(message) {
+                    Ok(message) => {
+                        Self::handle_signal(
+                            message,
+                            &passphrase,
+                            &signal,
+                            &pc,
+                            &mut candidates,
+                            &mut agreement,
+                        )
+                        .await
+                    }
+                    Err(err) => console_error!("could not open a signaling message: {:?}", err),
+                }
+            }
+            if state == State::Closed {
+                console_log!("call closed");
+                pc.close();
+                break;
+            }
+        }
+    }
 
-                        console_log!("callee sent answer back");
+    /// Acts on a signaling message accepted by the state machine.
+    async fn handle_signal(
+        message: Message,
+        passphrase: &str,
+        signal: &Signal,
+        pc: &RtcPeerConnection,
+        candidates: &mut PendingCandidates,
+        agreement: &mut KeyAgreement,
+    ) {
+        match message.event {
+            Event::Passphrase => {
+                let role = if message.data.eq("1") {
+                    Role::Caller
+                } else {
+                    Role::Callee
+                };
+                chat::set_role(role);
+                agreement.role = Some(role);
+            }
+            Event::KeyShare => {
+                let cipher = match (agreement.role, &agreement.room_id) {
+                    (Some(Role::Caller), _) => {
+                        let Some(key_exchange) = agreement.exchange.take() else {
+                            console_error!("received a key share without an exchange in progress");
+                            return;
+                        };
+                        key_exchange.finish(&message.data)
                     }
-                    Event::Answer => {
-                        // Caller receives answer from callee.
-                        console_log!("caller received answer");
-
-                        let mut answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-                        answer_obj.sdp(&message.data);
-                        let srd_promise = pc.set_remote_description(&answer_obj);
-                        JsFuture::from(srd_promise).await.unwrap();
-                        console_log!("pc: state {:?}", pc.signaling_state());
+                    (Some(Role::Callee), Some(room_id)) => {
+                        pake::respond(passphrase, room_id, &message.data).map(|(share, cipher)| {
+                            let message = Message {
+                                event: Event::KeyShare,
+                                data: share,
+                            };
+                            if let Err(err) = signal.send(&message) {
+                                console_error!("could not send key share: {:?}", err);
+                            }
+                            cipher
+                        })
                     }
-                    Event::IceCandidate => {
-                        console_log!("received a candidate");
-
-                        let candidate =
-                            RtcIceCandidate::new(&RtcIceCandidateInit::new(&message.data)).unwrap();
-                        let promise =
-                            pc.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&candidate));
-                        JsFuture::from(promise).await.unwrap();
+                    _ => {
+                        console_error!("received a key share before joining a room");
+                        return;
                     }
+                };
+                match cipher {
+                    Ok(cipher) => {
+                        if let Some(e2ee) = &agreement.e2ee {
+                            e2ee.set_key(cipher.media_key());
+                        }
+                        signal.set_cipher(cipher)
+                    }
+                    Err(err) => {
+                        console_error!("could not exchange signaling key: {}", err);
+                        return;
+                    }
+                }
+                console_log!("exchanged signaling key");
+
+                // If peer's role is caller, send its offer to callee.
+                if agreement.role == Some(Role::Caller) {
+                    console_log!("this is a caller");
+
+                    if let Err(err) = Self::send_offer(signal, pc).await {
+                        utils::show_error(&format!("could not send offer: {:?}", err));
+                        return;
+                    }
+                    console_log!("caller sent offer");
+                }
+            }
+            Event::Room => agreement.room_id = Some(message.data),
+            Event::Offer => {
+                // Callee receives offer from caller.
+                console_log!("callee received offer");
+
+                if let Err(err) = candidates
+                    .set_remote_description(pc, RtcSdpType::Offer, &message.data)
+                    .await
+                {
+                    console_error!("could not apply offer: {:?}", err);
+                    return;
+                }
+                console_log!("pc: state {:?}", pc.signaling_state());
+
+                // Callee returns answer to caller.
+                if let Err(err) = Self::send_answer(signal, pc).await {
+                    utils::show_error(&format!("could not send answer: {:?}", err));
+                    return;
+                }
+
+                console_log!("callee sent answer back");
+                verification::update(pc);
+            }
+            Event::Answer => {
+                // Caller receives answer from callee.
+                console_log!("caller received answer");
+
+                if let Err(err) = candidates
+                    .set_remote_description(pc, RtcSdpType::Answer, &message.data)
+                    .await
+                {
+                    console_error!("could not apply answer: {:?}", err);
+                    return;
                 }
+                console_log!("pc: state {:?}", pc.signaling_state());
+                verification::update(pc);
             }
+            Event::IceCandidate => {
+                console_log!("received a candidate");
+
+                if let Err(err) = candidates.add(pc, message.data).await {
+                    console_error!("could not add candidate: {:?}", err);
+                }
+            }
+            Event::ResumeToken => {
+                console_log!("received a resume token");
+                signal.set_resume_token(message.data);
+            }
+            Event::Resume => {
+                console_log!("resumed session, role: {}", message.data);
+                signal.resumed();
+            }
+            Event::JoinRequest => {
+                console_log!("a party knocks on the room");
+                knock::request(&message.data);
+            }
+            Event::PeerInfo => {
+                participant::show_remote(&message.data);
+                // Every callee joining tells about itself, a new one after the previous left included.
+                if agreement.role == Some(Role::Caller) {
+                    Self::start_key_exchange(passphrase, signal, agreement);
+                }
+            }
+            // Only peers send these, the state machine never accepts them.
+            Event::Join | Event::Admit | Event::Deny => {}
+            Event::Error => match serde_json::from_str::<ServerError>(&message.data) {
+                Ok(error) => console_error!(
+                    "signal server refused a request: {:?}, retry after {:?} seconds",
+                    error.code,
+                    error.retry_after
+                ),
+                Err(err) => console_error!("invalid server error: {}", err),
+            },
         }
     }
 
-    async fn send_offer(ws: &WebSocket, pc: &RtcPeerConnection) -> Result<(), JsValue> {
+    /// Starts a key exchange as caller, anew for every callee. Both parties exchange keys before negotiating,
+    /// so that signal server relays only ciphertext: caller starts the exchange, callee answers caller's share.
+    fn start_key_exchange(passphrase: &str, signal: &Signal, agreement: &mut KeyAgreement) {
+        let Some(room_id) = &agreement.room_id else {
+            console_error!("was assigned a role without a room");
+            return;
+        };
+        let key_exchange = KeyExchange::initiate(passphrase, room_id);
+        let message = Message {
+            event: Event::KeyShare,
+            data: key_exchange.share(),
+        };
+        if let Err(err) = signal.send(&message) {
+            console_error!("could not send key share: {:?}", err);
+        }
+        agreement.exchange = Some(key_exchange);
+    }
+
+    async fn send_offer(signal: &Signal, pc: &RtcPeerConnection) -> Result<(), JsValue> {
         let offer = JsFuture::from(pc.create_offer()).await?;
         let offer_sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))?
             .as_string()
-            .unwrap();
+            .ok_or("offer has no session description")?;
         console_log!("offer {:?}", offer_sdp);
 
         let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
@@ -175,27 +423,26 @@ impl Session {
             event: Event::Offer,
             data: offer_sdp,
         };
-        ws.send_with_str(&serde_json::to_string(&message).unwrap())
+        signal.send(&message)
     }
 
-    async fn send_answer(ws: &WebSocket, pc: &RtcPeerConnection) -> Result<(), JsValue> {
-        let answer = JsFuture::from(pc.create_answer()).await.unwrap();
-        let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))
-            .unwrap()
+    async fn send_answer(signal: &Signal, pc: &RtcPeerConnection) -> Result<(), JsValue> {
+        let answer = JsFuture::from(pc.create_answer()).await?;
+        let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))?
             .as_string()
-            .unwrap();
+            .ok_or("answer has no session description")?;
         console_log!("pc: answer {:?}", answer_sdp);
 
         let mut answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
         answer_obj.sdp(&answer_sdp);
         let sld_promise = pc.set_local_description(&answer_obj);
-        JsFuture::from(sld_promise).await.unwrap();
+        JsFuture::from(sld_promise).await?;
         console_log!("pc: state {:?}", pc.signaling_state());
 
         let message = Message {
             event: Event::Answer,
             data: answer_sdp,
         };
-        ws.send_with_str(&serde_json::to_string(&message).unwrap())
+        signal.send(&message)
     }
 }
@@ -0,0 +1,216 @@
+use crate::{console_error, console_log, pake::SignalCipher, ws_callbacks};
+use futures_channel::mpsc::UnboundedSender;
+use protocol::{Event, Message};
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use web_sys::WebSocket;
+
+/// Delay before reconnecting to signal server after the WebSocket is closed.
+const RECONNECT_DELAY_MS: i32 = 2000;
+
+/// Maximum consecutive reconnection attempts before giving up.
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+
+/// Most messages kept while the WebSocket is down, the oldest ones are dropped beyond.
+const MAX_QUEUED_MESSAGES: usize = 256;
+
+/// A connection to signal server, which survives WebSocket drops by resuming its session with a token.
+pub(crate) struct Signal {
+    ws_addr: String,
+    ws: RefCell<WebSocket>,
+    sender: UnboundedSender<Message>,
+
+    /// Token issued by signal server to resume the session with.
+    resume_token: RefCell<Option<String>>,
+    reconnect_attempts: RefCell<u32>,
+    /// Whether the WebSocket is open with the session joined or resumed, messages are queued otherwise.
+    live: RefCell<bool>,
+    /// Messages sent while the WebSocket is down, sent on once the session is resumed.
+    queue: RefCell<VecDeque<String>>,
+    /// Cipher of negotiation payloads, known once the key exchange with the other party completes.
+    cipher: RefCell<Option<SignalCipher>>,
+}
+
+impl Signal {
+    /// Connects to signal server and joins a session with a join message.
+    /// Messages received from signal server are forwarded to sender.
+    pub(crate) fn connect(
+        ws_addr: String,
+        join_message: String,
+        sender: UnboundedSender<Message>,
+    ) -> Result<Rc<Signal>, JsValue> {
+        let ws = WebSocket::new(&ws_addr)?;
+        let signal = Rc::new(Signal {
+            ws_addr,
+            ws: RefCell::new(ws.clone()),
+            sender,
+            resume_token: RefCell::new(None),
+            reconnect_attempts: RefCell::new(0),
+            live: RefCell::new(true),
+            queue: RefCell::new(VecDeque::new()),
+            cipher: RefCell::new(None),
+        });
+        Self::set_callbacks(&signal, &ws, join_message);
+        Ok(signal)
+    }
+
+    /// Sends a message to the other party through signal server, or queues it until the session is resumed
+    /// if the WebSocket is down. Negotiation payloads are sealed, they can't be sent before the key exchange
+    /// completes.
+    pub(crate) fn send(&self, message: &Message) -> Result<(), JsValue> {
+        let sealed;
+        let message = if Self::is_sealed(&message.event) {
+            let cipher = self.cipher.borrow();
+            let cipher = cipher
+                .as_ref()
+                .ok_or("signaling key is not exchanged yet")?;
+            sealed = Message {
+                event: message.event,
+                data: cipher.seal(&message.data),
+            };
+            &sealed
+        } else {
+            message
+        };
+        let text = serde_json::to_string(message).unwrap();
+
+        // A closing WebSocket drops messages silently, they are kept for the resumed session instead.
+        let ws = self.ws.borrow();
+        if !*self.live.borrow() || ws.ready_state() != WebSocket::OPEN {
+            self.enqueue(text);
+            return Ok(());
+        }
+        if let Err(err) = ws.send_with_str(&text) {
+            console_error!("queued a message the WebSocket refused: {:?}", err);
+            self.live.replace(false);
+            self.enqueue(text);
+        }
+        Ok(())
+    }
+
+    fn enqueue(&self, text: String) {
+        let mut queue = self.queue.borrow_mut();
+        if queue.len() >= MAX_QUEUED_MESSAGES {
+            console_error!(
+                "dropped a queued message, too many are waiting for the session to resume"
+            );
+            queue.pop_front();
+        }
+        queue.push_back(text);
+    }
+
+    /// Sends the messages queued while the WebSocket was down, in order.
+    fn flush(&self) {
+        let ws = self.ws.borrow();
+        loop {
+            let next = self.queue.borrow_mut().pop_front();
+            let Some(text) = next else {
+                return;
+            };
+            if let Err(err) = ws.send_with_str(&text) {
+                console_error!("could not send a queued message: {:?}", err);
+                self.live.replace(false);
+                self.queue.borrow_mut().push_front(text);
+                return;
+            }
+        }
+    }
+
+    /// Opens the payload of a message received from the other party.
+    pub(crate) fn open(&self, message: Message) -> Result<Message, JsValue> {
+        if !Self::is_sealed(&message.event) {
+            return Ok(message);
+        }
+        let cipher = self.cipher.borrow();
+        let cipher = cipher
+            .as_ref()
+            .ok_or("signaling key is not exchanged yet")?;
+        let data = cipher
+            .open(&message.data)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(Message {
+            event: message.event,
+            data,
+        })
+    }
+
+    /// Keeps the key exchanged with the other party.
+    pub(crate) fn set_cipher(&self, cipher: SignalCipher) {
+        self.cipher.replace(Some(cipher));
+    }
+
+    fn is_sealed(event: &Event) -> bool {
+        matches!(event, Event::Offer | Event::Answer | Event::IceCandidate)
+    }
+
+    /// Keeps the resume token, a dropped connection is resumed with it from now on.
+    pub(crate) fn set_resume_token(&self, token: String) {
+        self.resume_token.replace(Some(token));
+    }
+
+    /// Marks a reconnection as successful, and sends on what was queued meanwhile.
+    pub(crate) fn resumed(&self) {
+        self.reconnect_attempts.replace(0);
+        self.live.replace(true);
+        self.flush();
+    }
+
+    fn set_callbacks(signal: &Rc<Signal>, ws: &WebSocket, first_message: String) {
+        ws_callbacks::set_onopen(ws, first_message);
+        ws_callbacks::set_onerror(ws);
+        ws_callbacks::set_onmessage(ws, signal.sender.clone());
+
+        let weak = Rc::downgrade(signal);
+        ws_callbacks::set_onclose(ws, move |code| {
+            if let Some(signal) = weak.upgrade() {
+                signal.live.replace(false);
+                Self::schedule_reconnect(signal, code);
+            }
+        });
+    }
+
+    fn schedule_reconnect(signal: Rc<Signal>, code: u16) {
+        if code == protocol::CLOSE_INVALID_RESUME_TOKEN {
+            console_error!("session could not be resumed");
+            signal.resume_token.replace(None);
+            signal.queue.borrow_mut().clear();
+            return;
+        }
+        if signal.resume_token.borrow().is_none() {
+            return;
+        }
+        let attempts = *signal.reconnect_attempts.borrow();
+        if attempts >= MAX_RECONNECT_ATTEMPTS {
+            console_error!("giving up reconnecting after {} attempts", attempts);
+            return;
+        }
+        signal.reconnect_attempts.replace(attempts + 1);
+
+        let reconnect_callback = Closure::once_into_js(move || {
+            if let Err(err) = Self::reconnect(&signal) {
+                console_error!("could not reconnect: {:?}", err);
+            }
+        });
+        web_sys::window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(
+                reconnect_callback.unchecked_ref(),
+                RECONNECT_DELAY_MS,
+            )
+            .unwrap();
+    }
+
+    fn reconnect(signal: &Rc<Signal>) -> Result<(), JsValue> {
+        console_log!("reconnecting to signal server");
+
+        let token = signal.resume_token.borrow().clone().unwrap_or_default();
+        let message = Message {
+            event: Event::Resume,
+            data: token,
+        };
+        let ws = WebSocket::new(&signal.ws_addr)?;
+        Self::set_callbacks(signal, &ws, serde_json::to_string(&message).unwrap());
+        signal.ws.replace(ws);
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+//! Call state machine of a peer, driven by signaling messages and ICE connection changes.
+
+use protocol::Event;
+use std::fmt;
+use web_sys::RtcIceConnectionState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    /// Not connected to signal server yet.
+    Idle,
+    /// Passphrase sent, waiting for signal server to assign a role.
+    Joining,
+    /// Exchanging session descriptions and candidates with the other party.
+    Negotiating,
+    /// ICE connection established, media is flowing.
+    Connected,
+    /// The call is over, no more inputs are accepted.
+    Closed,
+}
+
+/// Inputs which drive state transitions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Input {
+    /// Connecting to signal server with a passphrase.
+    Join,
+    /// Signal server tells the id of the joined room.
+    Room,
+    /// Signal server assigned a role.
+    RoleAssigned,
+    /// Signal server issued a resume token.
+    ResumeToken,
+    /// Signal server resumed a dropped session.
+    Resumed,
+    /// Signal server refused a request.
+    ServerError,
+    /// The other party sent its share of the signaling key exchange.
+    KeyShare,
+    /// A party knocks on the room of caller.
+    JoinRequest,
+    /// Signal server tells about the other party.
+    PeerInfo,
+    /// An event which only peers send, signal server never sends it back.
+    Unexpected,
+    Offer,
+    Answer,
+    IceCandidate,
+    IceConnected,
+    IceDisconnected,
+    Close,
+}
+
+/// An input which is not acceptable in a state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InvalidTransition {
+    pub(crate) from: State,
+    pub(crate) input: Input,
+}
+
+impl State {
+    /// Returns the next state after an input, or an error if the input is not acceptable.
+    pub(crate) fn transition(self, input: Input) -> Result<State, InvalidTransition> {
+        use Input::*;
+        use State::*;
+
+        let next = match (self, input) {
+            (Closed, _) => None,
+            (_, Close) => Some(Closed),
+            (Idle, Join) => Some(Joining),
+            (Joining, Room) => Some(Joining),
+            (Joining, RoleAssigned) => Some(Negotiating),
+            (Joining | Negotiating | Connected, ResumeToken | Resumed | ServerError) => Some(self),
+            (Negotiating, KeyShare | Offer | Answer | IceCandidate | IceDisconnected) => {
+                Some(Negotiating)
+            }
+            (Negotiating, IceConnected) => Some(Connected),
+            // Caller is asked in while waiting for the other party, or for a new one after it left.
+            (Negotiating | Connected, JoinRequest | PeerInfo) => Some(self),
+            (Connected, IceCandidate | IceConnected) => Some(Connected),
+            // A new session description, a lost connection or a new callee's key exchange restarts negotiation,
+            // ICE may take seconds to notice that the previous callee is gone.
+            (Connected, KeyShare | Offer | Answer | IceDisconnected) => Some(Negotiating),
+            _ => None,
+        };
+        next.ok_or(InvalidTransition { from: self, input })
+    }
+}
+
+impl Input {
+    /// Maps an ICE connection state to an input, transient states are not inputs.
+    pub(crate) fn from_ice_connection_state(state: RtcIceConnectionState) -> Option<Input> {
+        match state {
+            RtcIceConnectionState::Connected | RtcIceConnectionState::Completed => {
+                Some(Input::IceConnected)
+            }
+            RtcIceConnectionState::Disconnected | RtcIceConnectionState::Failed => {
+                Some(Input::IceDisconnected)
+            }
+            RtcIceConnectionState::Closed => Some(Input::Close),
+            _ => None,
+        }
+    }
+}
+
+impl From<&Event> for Input {
+    fn from(event: &Event) -> Self {
+        match event {
+            Event::Passphrase => Input::RoleAssigned,
+            Event::Offer => Input::Offer,
+            Event::Answer => Input::Answer,
+            Event::IceCandidate => Input::IceCandidate,
+            Event::ResumeToken => Input::ResumeToken,
+            Event::Resume => Input::Resumed,
+            Event::Error => Input::ServerError,
+            Event::KeyShare => Input::KeyShare,
+            Event::JoinRequest => Input::JoinRequest,
+            Event::PeerInfo => Input::PeerInfo,
+            Event::Room => Input::Room,
+            Event::Join | Event::Admit | Event::Deny => Input::Unexpected,
+        }
+    }
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid input {:?} in state {:?}", self.input, self.from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Input, InvalidTransition, State};
+
+    fn run(inputs: &[Input]) -> Result<State, InvalidTransition> {
+        inputs
+            .iter()
+            .try_fold(State::Idle, |state, &input| state.transition(input))
+    }
+
+    #[test]
+    fn caller_flow() {
+        let state = run(&[
+            Input::Join,
+            Input::Room,
+            Input::RoleAssigned,
+            Input::ResumeToken,
+            Input::JoinRequest,
+            Input::KeyShare,
+            Input::Answer,
+            Input::IceCandidate,
+            Input::IceConnected,
+        ]);
+        assert_eq!(state, Ok(State::Connected));
+    }
+
+    #[test]
+    fn callee_flow() {
+        let state = run(&[
+            Input::Join,
+            Input::Room,
+            Input::RoleAssigned,
+            Input::PeerInfo,
+            Input::KeyShare,
+            Input::IceCandidate,
+            Input::Offer,
+            Input::IceConnected,
+            Input::IceCandidate,
+        ]);
+        assert_eq!(state, Ok(State::Connected));
+    }
+
+    #[test]
+    fn renegotiate_after_disconnection() {
+        let state = run(&[
+            Input::Join,
+            Input::RoleAssigned,
+            Input::Offer,
+            Input::IceConnected,
+            Input::IceDisconnected,
+        ]);
+        assert_eq!(state, Ok(State::Negotiating));
+        assert_eq!(
+            State::Connected.transition(Input::Offer),
+            Ok(State::Negotiating)
+        );
+    }
+
+    #[test]
+    fn new_callee_while_connected() {
+        let state = run(&[
+            Input::Join,
+            Input::Room,
+            Input::RoleAssigned,
+            Input::PeerInfo,
+            Input::KeyShare,
+            Input::Answer,
+            Input::IceConnected,
+            // The previous callee left, a new one joins before ICE notices.
+            Input::PeerInfo,
+            Input::KeyShare,
+        ]);
+        assert_eq!(state, Ok(State::Negotiating));
+        let state = state.and_then(|state| {
+            [Input::Answer, Input::IceCandidate, Input::IceConnected]
+                .into_iter()
+                .try_fold(state, State::transition)
+        });
+        assert_eq!(state, Ok(State::Connected));
+    }
+
+    #[test]
+    fn resume_keeps_state() {
+        for state in [State::Joining, State::Negotiating, State::Connected] {
+            assert_eq!(state.transition(Input::Resumed), Ok(state));
+        }
+    }
+
+    #[test]
+    fn reject_out_of_order_inputs() {
+        assert_eq!(
+            State::Idle.transition(Input::Offer),
+            Err(InvalidTransition {
+                from: State::Idle,
+                input: Input::Offer
+            })
+        );
+        assert!(State::Joining.transition(Input::IceCandidate).is_err());
+        assert!(State::Negotiating.transition(Input::Join).is_err());
+        assert!(State::Connected.transition(Input::RoleAssigned).is_err());
+        assert!(State::Joining.transition(Input::JoinRequest).is_err());
+        assert!(State::Negotiating.transition(Input::Unexpected).is_err());
+    }
+
+    #[test]
+    fn ice_connection_state_inputs() {
+        use web_sys::RtcIceConnectionState;
+
+        assert_eq!(
+            Input::from_ice_connection_state(RtcIceConnectionState::Completed),
+            Some(Input::IceConnected)
+        );
+        assert_eq!(
+            Input::from_ice_connection_state(RtcIceConnectionState::Failed),
+            Some(Input::IceDisconnected)
+        );
+        assert_eq!(
+            Input::from_ice_connection_state(RtcIceConnectionState::Checking),
+            None
+        );
+    }
+
+    #[test]
+    fn closed_is_final() {
+        assert_eq!(run(&[Input::Join, Input::Close]), Ok(State::Closed));
+        assert!(State::Closed.transition(Input::Join).is_err());
+        assert!(State::Closed.transition(Input::Close).is_err());
+    }
+}
@@ -20,6 +20,16 @@ macro_rules! console_error {
     ($($t:tt)*) => ($crate::utils::error(&format_args!($($t)*).to_string()))
 }
 
+/// Shows an error which stops the call on the page, besides logging it.
+pub(crate) fn show_error(text: &str) {
+    error(text);
+    let document = web_sys::window().unwrap().document().unwrap();
+    if let Some(element) = document.get_element_by_id("callError") {
+        element.set_text_content(Some(text));
+        element.remove_attribute("hidden").ok();
+    }
+}
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
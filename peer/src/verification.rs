@@ -0,0 +1,117 @@
+//! Verification of a call by comparing its short authentication string with the other party out of band.
+
+use crate::{
+    console_error,
+    sas::{self, Sas},
+};
+use js_sys::Function;
+use std::cell::RefCell;
+use wasm_bindgen::prelude::*;
+use web_sys::RtcPeerConnection;
+
+thread_local! {
+    static VERIFICATION: RefCell<Verification> = const {
+        RefCell::new(Verification {
+            sas: None,
+            verified: false,
+            callback: None,
+        })
+    };
+}
+
+struct Verification {
+    /// String of the negotiated fingerprints, known once both session descriptions are set.
+    sas: Option<Sas>,
+    /// Whether the user marked the string as matching the other party's.
+    verified: bool,
+    /// A JS function called whenever the string or its verification changes.
+    callback: Option<Function>,
+}
+
+/// Derives the short authentication string once both session descriptions are set.
+/// A renegotiation with other fingerprints resets the verification.
+pub(crate) fn update(pc: &RtcPeerConnection) {
+    let local = pc
+        .local_description()
+        .and_then(|description| sas::fingerprint(&description.sdp()));
+    let remote = pc
+        .remote_description()
+        .and_then(|description| sas::fingerprint(&description.sdp()));
+    let (Some(local), Some(remote)) = (local, remote) else {
+        return;
+    };
+
+    let sas = Sas::new(&local, &remote);
+    let changed = VERIFICATION.with(|verification| {
+        let mut verification = verification.borrow_mut();
+        if verification.sas.as_ref() == Some(&sas) {
+            return false;
+        }
+        verification.sas = Some(sas);
+        verification.verified = false;
+        true
+    });
+    if changed {
+        render();
+    }
+}
+
+/// Marks the call verified, once the user compared its short authentication string with the other party.
+#[wasm_bindgen]
+pub fn mark_verified() -> Result<(), JsValue> {
+    VERIFICATION.with(|verification| {
+        let mut verification = verification.borrow_mut();
+        if verification.sas.is_none() {
+            return Err("the call is not negotiated yet");
+        }
+        verification.verified = true;
+        Ok(())
+    })?;
+    render();
+    Ok(())
+}
+
+/// Registers a JS function called with `(emoji, words, verified)` whenever the short authentication string
+/// or its verification changes.
+#[wasm_bindgen]
+pub fn on_sas(callback: Function) {
+    VERIFICATION.with(|verification| verification.borrow_mut().callback = Some(callback));
+}
+
+/// Shows the short authentication string in the page and hands it to the registered callback.
+fn render() {
+    let (sas, verified, callback) = VERIFICATION.with(|verification| {
+        let verification = verification.borrow();
+        (
+            verification.sas.clone(),
+            verification.verified,
+            verification.callback.clone(),
+        )
+    });
+    let Some(sas) = sas else {
+        return;
+    };
+    let (emoji, words) = (sas.emoji(), sas.words());
+    let status = if verified { "verified" } else { "not verified" };
+
+    let document = web_sys::window().unwrap().document().unwrap();
+    for (id, text) in [("sasEmoji", &emoji), ("sasWords", &words)] {
+        if let Some(element) = document.get_element_by_id(id) {
+            element.set_text_content(Some(text));
+        }
+    }
+    if let Some(element) = document.get_element_by_id("sasStatus") {
+        element.set_text_content(Some(status));
+    }
+
+    if let Some(callback) = callback {
+        let args = [
+            JsValue::from_str(&emoji),
+            JsValue::from_str(&words),
+            JsValue::from_bool(verified),
+        ];
+        if let Err(err) = callback.apply(&JsValue::NULL, &args.iter().collect()) {
+            console_error!("SAS callback failed: {:?}", err);
+        }
+    }
+}
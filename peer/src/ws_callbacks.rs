@@ -2,7 +2,7 @@ use crate::{console_error, console_log};
 use futures_channel::mpsc::UnboundedSender;
 use protocol::Message;
 use wasm_bindgen::{prelude::Closure, JsCast};
-use web_sys::{ErrorEvent, MessageEvent, WebSocket};
+use web_sys::{CloseEvent, ErrorEvent, MessageEvent, WebSocket};
 
 pub(crate) fn set_onopen(ws: &WebSocket, message: String) {
     let ws_clone = ws.clone();
@@ -39,9 +39,11 @@ pub(crate) fn set_onerror(ws: &WebSocket) {
     onerror_callback.forget();
 }
 
-pub(crate) fn set_onclose(ws: &WebSocket) {
-    let onclose_callback = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
-        console_log!("WebSocket closed: {:?}", e)
+/// Sets a close callback, which is called with the close code.
+pub(crate) fn set_onclose(ws: &WebSocket, mut on_close: impl FnMut(u16) + 'static) {
+    let onclose_callback = Closure::<dyn FnMut(_)>::new(move |e: CloseEvent| {
+        console_log!("WebSocket closed: {} {}", e.code(), e.reason());
+        on_close(e.code());
     });
     ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
     onclose_callback.forget();
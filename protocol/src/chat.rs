@@ -0,0 +1,41 @@
+//! Chat messages exchanged between peers over a data channel.
+
+use crate::Role;
+use serde::{Deserialize, Serialize};
+
+/// Everything sent over the chat data channel is wrapped in an envelope,
+/// so that new kinds of payloads can be added without breaking older peers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum Envelope {
+    Chat(ChatMessage),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub sender: Role,
+    /// Milliseconds since unix epoch when the message is sent.
+    pub timestamp: u64,
+    pub text: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatMessage, Envelope};
+    use crate::Role;
+
+    #[test]
+    fn envelope_wire_format() {
+        let envelope = Envelope::Chat(ChatMessage {
+            sender: Role::Callee,
+            timestamp: 1_700_000_000_000,
+            text: "hi".into(),
+        });
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"Chat","payload":{"sender":"Callee","timestamp":1700000000000,"text":"hi"}}"#
+        );
+        assert_eq!(serde_json::from_str::<Envelope>(&json).unwrap(), envelope);
+    }
+}
@@ -0,0 +1,259 @@
+//! Binary framing of file transfers between peers over a data channel.
+//!
+//! Every frame starts with a one byte tag followed by the transfer id as a big endian `u32`:
+//!
+//! | Frame    | Layout after tag and transfer id                                     |
+//! |----------|----------------------------------------------------------------------|
+//! | Manifest | size `u64`, chunk size `u32`, SHA-256 of the file (32 bytes), UTF-8 name |
+//! | Resume   | index of the next chunk wanted by receiver `u32`                     |
+//! | Chunk    | chunk index `u32`, chunk data                                        |
+//! | Complete | nothing                                                              |
+//! | Failed   | nothing                                                              |
+//!
+//! A sender announces a file with `Manifest`, the receiver answers with `Resume` telling which chunk to start from,
+//! which is not 0 if it holds part of the same file from an interrupted transfer. After the last chunk,
+//! the receiver verifies the file against the SHA-256 in manifest and answers with `Complete` or `Failed`.
+
+use std::fmt;
+
+const TAG_MANIFEST: u8 = 0;
+const TAG_RESUME: u8 = 1;
+const TAG_CHUNK: u8 = 2;
+const TAG_COMPLETE: u8 = 3;
+const TAG_FAILED: u8 = 4;
+
+/// Length of tag and transfer id.
+const HEADER_LEN: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Manifest(Manifest),
+    Resume { transfer_id: u32, next_chunk: u32 },
+    Chunk(Chunk),
+    Complete { transfer_id: u32 },
+    Failed { transfer_id: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub transfer_id: u32,
+    pub name: String,
+    /// File size in bytes.
+    pub size: u64,
+    /// Size of every chunk but the last one in bytes.
+    pub chunk_size: u32,
+    pub sha256: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub transfer_id: u32,
+    pub index: u32,
+    pub data: Vec<u8>,
+}
+
+/// A frame could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame is shorter than its layout requires.
+    Truncated,
+    UnknownTag(u8),
+    /// The file name in manifest is not valid UTF-8.
+    InvalidName,
+}
+
+impl Frame {
+    pub fn transfer_id(&self) -> u32 {
+        match self {
+            Frame::Manifest(manifest) => manifest.transfer_id,
+            Frame::Chunk(chunk) => chunk.transfer_id,
+            Frame::Resume { transfer_id, .. }
+            | Frame::Complete { transfer_id }
+            | Frame::Failed { transfer_id } => *transfer_id,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let tag = match self {
+            Frame::Manifest(_) => TAG_MANIFEST,
+            Frame::Resume { .. } => TAG_RESUME,
+            Frame::Chunk(_) => TAG_CHUNK,
+            Frame::Complete { .. } => TAG_COMPLETE,
+            Frame::Failed { .. } => TAG_FAILED,
+        };
+        let mut bytes = vec![tag];
+        bytes.extend_from_slice(&self.transfer_id().to_be_bytes());
+
+        match self {
+            Frame::Manifest(manifest) => {
+                bytes.extend_from_slice(&manifest.size.to_be_bytes());
+                bytes.extend_from_slice(&manifest.chunk_size.to_be_bytes());
+                bytes.extend_from_slice(&manifest.sha256);
+                bytes.extend_from_slice(manifest.name.as_bytes());
+            }
+            Frame::Resume { next_chunk, .. } => bytes.extend_from_slice(&next_chunk.to_be_bytes()),
+            Frame::Chunk(chunk) => {
+                bytes.extend_from_slice(&chunk.index.to_be_bytes());
+                bytes.extend_from_slice(&chunk.data);
+            }
+            Frame::Complete { .. } | Frame::Failed { .. } => {}
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Frame, FrameError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(FrameError::Truncated);
+        }
+        let transfer_id = read_u32(bytes, 1)?;
+        let body = &bytes[HEADER_LEN..];
+
+        match bytes[0] {
+            TAG_MANIFEST => {
+                let size = u64::from_be_bytes(read_array(body, 0)?);
+                let chunk_size = read_u32(body, 8)?;
+                let sha256 = read_array(body, 12)?;
+                let name =
+                    String::from_utf8(body[44..].to_vec()).map_err(|_| FrameError::InvalidName)?;
+                Ok(Frame::Manifest(Manifest {
+                    transfer_id,
+                    name,
+                    size,
+                    chunk_size,
+                    sha256,
+                }))
+            }
+            TAG_RESUME => Ok(Frame::Resume {
+                transfer_id,
+                next_chunk: read_u32(body, 0)?,
+            }),
+            TAG_CHUNK => Ok(Frame::Chunk(Chunk {
+                transfer_id,
+                index: read_u32(body, 0)?,
+                data: body[4..].to_vec(),
+            })),
+            TAG_COMPLETE => Ok(Frame::Complete { transfer_id }),
+            TAG_FAILED => Ok(Frame::Failed { transfer_id }),
+            tag => Err(FrameError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Manifest {
+    /// Number of chunks the file is split into.
+    pub fn chunk_count(&self) -> u32 {
+        if self.chunk_size == 0 {
+            return 0;
+        }
+        self.size.div_ceil(self.chunk_size as u64) as u32
+    }
+
+    /// Byte range of a chunk in the file.
+    pub fn chunk_range(&self, index: u32) -> std::ops::Range<usize> {
+        let start = index as u64 * self.chunk_size as u64;
+        let end = (start + self.chunk_size as u64).min(self.size);
+        start as usize..end as usize
+    }
+
+    /// Tells whether `len` bytes are the chunk at `index`: the chunk is in the file and has the size of its range.
+    pub fn is_chunk(&self, index: u32, len: usize) -> bool {
+        index < self.chunk_count() && self.chunk_range(index).len() == len
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "truncated frame"),
+            FrameError::UnknownTag(tag) => write!(f, "unknown frame tag: {}", tag),
+            FrameError::InvalidName => write!(f, "file name is not valid UTF-8"),
+        }
+    }
+}
+
+fn read_array<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], FrameError> {
+    bytes
+        .get(offset..offset + N)
+        .and_then(|slice| slice.try_into().ok())
+        .ok_or(FrameError::Truncated)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, FrameError> {
+    read_array(bytes, offset).map(u32::from_be_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunk, Frame, FrameError, Manifest};
+
+    fn manifest() -> Manifest {
+        Manifest {
+            transfer_id: 7,
+            name: "report ä.pdf".into(),
+            size: 40_000,
+            chunk_size: 16_384,
+            sha256: [0xab; 32],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let frames = [
+            Frame::Manifest(manifest()),
+            Frame::Resume {
+                transfer_id: 7,
+                next_chunk: 2,
+            },
+            Frame::Chunk(Chunk {
+                transfer_id: 7,
+                index: 1,
+                data: vec![1, 2, 3],
+            }),
+            Frame::Complete { transfer_id: 7 },
+            Frame::Failed { transfer_id: 7 },
+        ];
+        for frame in frames {
+            assert_eq!(Frame::decode(&frame.encode()), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn chunk_layout() {
+        let frame = Frame::Chunk(Chunk {
+            transfer_id: 1,
+            index: 2,
+            data: vec![0xff],
+        });
+        assert_eq!(frame.encode(), [2, 0, 0, 0, 1, 0, 0, 0, 2, 0xff]);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        assert_eq!(Frame::decode(&[2, 0, 0]), Err(FrameError::Truncated));
+        assert_eq!(
+            Frame::decode(&[9, 0, 0, 0, 1]),
+            Err(FrameError::UnknownTag(9))
+        );
+        let mut manifest = Frame::Manifest(manifest()).encode();
+        manifest.truncate(30);
+        assert_eq!(Frame::decode(&manifest), Err(FrameError::Truncated));
+    }
+
+    #[test]
+    fn chunks() {
+        let manifest = manifest();
+        assert_eq!(manifest.chunk_count(), 3);
+        assert_eq!(manifest.chunk_range(0), 0..16_384);
+        assert_eq!(manifest.chunk_range(2), 32_768..40_000);
+        assert!(manifest.is_chunk(0, 16_384));
+        assert!(manifest.is_chunk(2, 7_232));
+        assert!(!manifest.is_chunk(2, 16_384));
+        assert!(!manifest.is_chunk(3, 0));
+
+        let empty = Manifest {
+            size: 0,
+            ..manifest
+        };
+        assert_eq!(empty.chunk_count(), 0);
+    }
+}
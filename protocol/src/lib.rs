@@ -1,5 +1,11 @@
+pub mod chat;
+pub mod file;
+
 use serde::{Deserialize, Serialize};
 
+/// Version of the signaling protocol, bumped on changes which break peers of a previous version.
+pub const PROTOCOL_VERSION: u32 = 2;
+
 /// A general Message used by WebSocket data exchange.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
@@ -7,10 +13,153 @@ pub struct Message {
     pub data: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Event {
     Passphrase,
     Offer,
     Answer,
     IceCandidate,
+    /// Sent by server after role assignment, data is a token to resume the session with.
+    ResumeToken,
+    /// Sent by a reconnecting peer as its first message, data is the resume token.
+    /// The server replies with the same event, data is the resumed role.
+    Resume,
+    /// Sent by server when it refuses a request, data is a serialized `ServerError`.
+    Error,
+    /// Relayed between peers before negotiation, data is the peer's share of the key exchange
+    /// which encrypts `Offer`, `Answer` and `IceCandidate` data.
+    KeyShare,
+    /// Sent by a joining peer as its first message instead of a bare passphrase, data is a serialized `Join`.
+    Join,
+    /// Sent by server to caller when a party knocks on its room, data is the party's serialized `PeerInfo`.
+    /// Nothing is relayed between them until caller answers with `Admit` or `Deny`.
+    JoinRequest,
+    /// Sent by caller to let a knocking party in, data is empty.
+    Admit,
+    /// Sent by caller to turn a knocking party away, data is empty.
+    Deny,
+    /// Sent by server to each party once the other one joins, data is the other party's serialized `PeerInfo`.
+    PeerInfo,
+    /// Sent by server right before assigning a role, data is the id of the room, which peers bind their key exchange to.
+    Room,
+}
+
+/// The first message of a joining peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Join {
+    pub passphrase: String,
+    /// What the other party is told about this one.
+    #[serde(default)]
+    pub info: PeerInfo,
+    /// Whether a party creating the room wants the other one to knock before joining.
+    #[serde(default)]
+    pub knock: bool,
+}
+
+/// What a party tells about itself when joining, all of it is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    /// Name labelling the party's video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Color of the party's avatar, in the form of `#rrggbb`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Client software of the party, such as `peer/0.1.0`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+}
+
+/// WebSocket close code used by server when a resume token is unknown or expired.
+pub const CLOSE_INVALID_RESUME_TOKEN: u16 = 4001;
+
+/// WebSocket close code used by server when a connection or join exceeds a rate limit.
+pub const CLOSE_RATE_LIMITED: u16 = 4002;
+
+/// WebSocket close code used by server when a passphrase is too weak, its room is full, an invite is for another room,
+/// or a knocking party is not admitted.
+pub const CLOSE_JOIN_REFUSED: u16 = 4003;
+
+/// WebSocket close code used by server when it can't relay messages anymore, the session may be resumed.
+pub const CLOSE_UNAVAILABLE: u16 = 4004;
+
+/// WebSocket close code used by server when a resume token belongs to a session which is still connected.
+/// Its connection may just not be noticed as dropped yet, resuming may be tried again later.
+pub const CLOSE_SESSION_IN_USE: u16 = 4005;
+
+/// An error reported by server with an `Error` event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    /// Seconds after which a refused request may be retried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Too many connections, joins or messages in a short time.
+    RateLimited,
+    /// A message is larger than server accepts, it is dropped.
+    MessageTooLarge,
+    /// A passphrase is too short or too simple, a generated room code may be used instead.
+    WeakPassphrase,
+    /// Both roles of a room are taken.
+    RoomFull,
+    /// The invite the client connected with is for another room.
+    Unauthorized,
+    /// Caller denied a knocking party, or didn't answer in time.
+    JoinDenied,
+    /// Server can't relay messages of the other party anymore.
+    Unavailable,
+}
+
+/// A room code generated by server, to be used as a passphrase.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomCode {
+    pub code: String,
+    /// Id of the room of the code, to query its status with.
+    pub id: String,
+    /// A token letting its holder join only this room, issued when server requires authentication.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite: Option<String>,
+}
+
+/// Status of a room, as reported by `GET /rooms/:id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomStatus {
+    pub state: RoomState,
+    /// Parties holding a role, including those disconnected who may still resume.
+    pub participants: u8,
+    /// Unix timestamp of the first join of the parties in the room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomState {
+    /// Nobody is in the room.
+    Empty,
+    /// One party waits for the other one.
+    Waiting,
+    /// Both roles are taken.
+    Full,
+}
+
+/// An ICE server handed to peers by signal server, in the shape of WebRTC `RTCIceServer`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Role of a peer in a call, the one who joins first is caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Caller,
+    Callee,
 }
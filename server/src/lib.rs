@@ -0,0 +1,79 @@
+//! A signal server for local development: the `/signal` protocol of the worker on a native server, with an
+//! in-memory store instead of Redis, serving the page and peer of `static/`.
+//!
+//! Rate limits, authentication, origin checks and metrics of the worker are left out, the server is not meant
+//! to be exposed to the internet.
+
+mod session;
+
+use axum::{
+    extract::{State, WebSocketUpgrade},
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use signaling::{room, MemoryStore};
+use std::{path::PathBuf, sync::Arc};
+use tower_http::services::ServeDir;
+
+/// STUN server handed to peers by `/ice-servers`.
+const STUN_URL: &str = "stun:stun.l.google.com:19302";
+
+/// Configuration of the server.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Key of the keyed hash deriving room ids from passphrases.
+    pub passphrase_secret: String,
+    /// Directory of the page and the built peer.
+    pub static_dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+struct AppState {
+    store: MemoryStore,
+    passphrase_secret: Arc<str>,
+}
+
+/// Routes of the server, the same as the worker's which peers use.
+pub fn router(config: Config) -> Router {
+    let state = AppState {
+        store: MemoryStore::default(),
+        passphrase_secret: config.passphrase_secret.into(),
+    };
+    Router::new()
+        .route("/signal", get(signal))
+        .route("/rooms", post(create_room))
+        .route("/ice-servers", get(ice_servers))
+        .route("/healthz", get(|| async { "ok" }))
+        .fallback_service(ServeDir::new(config.static_dir))
+        .with_state(state)
+}
+
+/// Generates a random secret, rooms only live as long as the process anyway.
+pub fn random_secret() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("could not generate random bytes");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+async fn signal(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+    ws.on_upgrade(move |socket| session::run(socket, state.store, state.passphrase_secret))
+}
+
+async fn create_room(State(state): State<AppState>) -> Json<protocol::RoomCode> {
+    let code = room::generate_code();
+    let id = room::room_id(&state.passphrase_secret, &code);
+    Json(protocol::RoomCode {
+        code,
+        id,
+        invite: None,
+    })
+}
+
+async fn ice_servers() -> Json<Vec<protocol::IceServer>> {
+    Json(vec![protocol::IceServer {
+        urls: vec![STUN_URL.into()],
+        username: None,
+        credential: None,
+    }])
+}
@@ -0,0 +1,32 @@
+use hangout_server::{random_secret, router, Config};
+use std::{env, net::IpAddr};
+use tokio::net::TcpListener;
+
+/// Port of `wrangler dev`, which the peer connects to.
+const DEFAULT_PORT: u16 = 8787;
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let host: IpAddr = env::var("HOST")
+        .ok()
+        .and_then(|host| host.parse().ok())
+        .unwrap_or([127, 0, 0, 1].into());
+    let port = env::var("PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(DEFAULT_PORT);
+    let config = Config {
+        passphrase_secret: env::var("PASSPHRASE_SECRET").unwrap_or_else(|_| random_secret()),
+        static_dir: env::var("STATIC_DIR")
+            .unwrap_or_else(|_| "static".into())
+            .into(),
+    };
+
+    let listener = TcpListener::bind((host, port)).await?;
+    println!("listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(config))
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+}
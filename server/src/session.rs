@@ -0,0 +1,89 @@
+//! Signaling sessions of the worker over axum WebSockets, with an in-memory store and tokio's clock.
+
+use axum::extract::ws::{CloseFrame, Message as WsMessage, WebSocket};
+use futures::{stream::SplitStream, SinkExt, StreamExt};
+use signaling::{Clock, Incoming, MemoryStore, Session, Socket};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// How often the other party's channel and the answer to a knock are polled, the in-memory store is cheap to poll.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Messages to the client, written to the WebSocket by a single task.
+struct Client(UnboundedSender<WsMessage>);
+
+struct Messages(SplitStream<WebSocket>);
+
+struct TokioClock;
+
+/// Runs a session until the client leaves.
+pub(crate) async fn run(socket: WebSocket, store: MemoryStore, passphrase_secret: Arc<str>) {
+    let (mut sink, incoming) = socket.split();
+    let (outgoing, mut outgoing_receiver) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outgoing_receiver.recv().await {
+            let is_close = matches!(message, WsMessage::Close(_));
+            if sink.send(message).await.is_err() || is_close {
+                break;
+            }
+        }
+    });
+
+    let session = Session::new(
+        store,
+        Client(outgoing),
+        TokioClock,
+        (),
+        passphrase_secret.to_string(),
+    )
+    .poll_interval(POLL_INTERVAL);
+    session.run(&mut Messages(incoming)).await;
+    // The writer ends once the session dropped its sender.
+    drop(session);
+    writer.await.ok();
+}
+
+impl Socket for Client {
+    fn send(&self, text: &str) -> Result<(), String> {
+        self.0
+            .send(WsMessage::Text(text.into()))
+            .map_err(|error| error.to_string())
+    }
+
+    fn close(&self, code: u16, reason: &str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        self.0.send(WsMessage::Close(Some(frame))).ok();
+    }
+}
+
+impl Incoming for Messages {
+    async fn next(&mut self) -> Option<String> {
+        while let Some(Ok(message)) = self.0.next().await {
+            match message {
+                WsMessage::Text(text) => return Some(text.to_string()),
+                WsMessage::Close(_) => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+impl Clock for TokioClock {
+    fn now_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
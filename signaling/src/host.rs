@@ -0,0 +1,139 @@
+//! What a host plugs into sessions.
+//!
+//! Methods return futures instead of being `async`, hosts on a single thread such as the worker don't need
+//! them to be `Send`, while a multithreaded host gets `Send` futures from its own implementations.
+
+use protocol::{ErrorCode, Event, Role, ServerError};
+use std::{future::Future, time::Duration};
+
+/// The WebSocket of a client.
+pub trait Socket {
+    /// Sends a text message, fails if the connection is closing.
+    fn send(&self, text: &str) -> Result<(), String>;
+
+    fn close(&self, code: u16, reason: &str);
+}
+
+/// Messages from a client.
+pub trait Incoming {
+    /// Waits for the next text message, None once the client leaves.
+    fn next(&mut self) -> impl Future<Output = Option<String>>;
+}
+
+/// Time as seen by sessions.
+pub trait Clock {
+    /// Current unix timestamp in milliseconds.
+    fn now_ms(&self) -> u64;
+
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()>;
+}
+
+/// Keys and lists shared by all sessions, with the semantics of the Redis commands of the same names.
+/// Errors are described by a message.
+pub trait Store {
+    /// Sets a key unless it exists, returns whether it was set.
+    fn set_nx(&self, key: &str, value: &str) -> impl Future<Output = Result<bool, String>>;
+
+    fn set(&self, key: &str, value: &str) -> impl Future<Output = Result<(), String>>;
+
+    /// Returns the string of a key, None if it doesn't exist.
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<String>, String>>;
+
+    fn del(&self, keys: &[&str]) -> impl Future<Output = Result<(), String>>;
+
+    /// Pushes an element to the front of a list, creating it if needed.
+    fn push(&self, key: &str, element: &str) -> impl Future<Output = Result<(), String>>;
+
+    /// Pops the element at the back of a list, the oldest one pushed.
+    fn pop(&self, key: &str) -> impl Future<Output = Result<Option<String>, String>>;
+
+    /// Puts a popped element back at the back of a list, so that it is the next one popped.
+    fn requeue(&self, key: &str, element: &str) -> impl Future<Output = Result<(), String>>;
+
+    /// Sets keys to expire after a number of seconds.
+    fn expire(&self, keys: &[&str], seconds: u32) -> impl Future<Output = Result<(), String>>;
+
+    /// Cancels the expiration of keys, returns how many of them exist.
+    fn persist(&self, keys: &[&str]) -> impl Future<Output = Result<u32, String>>;
+}
+
+/// Limits and observers of a host, all of them let everything through and observe nothing by default.
+pub trait Hooks {
+    /// Checks a client about to join, before its passphrase is looked at.
+    fn check_join(&self) -> impl Future<Output = Result<(), Rejection>> {
+        async { Ok(()) }
+    }
+
+    /// Checks a client about to join or resume a room, before it takes a role.
+    fn check_room(&self, _room_id: &str) -> impl Future<Output = Result<(), Rejection>> {
+        async { Ok(()) }
+    }
+
+    /// Checks a message from the client to its room before it is relayed, its size is checked by the session
+    /// already. The message is dropped on error, and the client is told why.
+    fn check_message(&self, _room_id: &str) -> impl Future<Output = Result<(), ServerError>> {
+        async { Ok(()) }
+    }
+
+    fn report(&self, _report: Report) -> impl Future<Output = ()> {
+        async {}
+    }
+}
+
+/// Neither limits nor observes sessions, as for local development and tests.
+impl Hooks for () {}
+
+/// Why a host turns a join away.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    /// The join is refused, and the connection is closed with `CLOSE_JOIN_REFUSED`.
+    /// It is reported as `Report::JoinRefused` like the refusals of sessions.
+    Refused(ErrorCode),
+    /// The client is over a rate limit, and the connection is closed with `CLOSE_RATE_LIMITED`.
+    RateLimited(ServerError),
+}
+
+/// What happens in a session, for hosts to log and count.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Report {
+    /// The party took a role in a room, it may still have to knock.
+    Joined(Role),
+    /// The party was told its role, after being admitted if it had to knock.
+    RoleAssigned(Role),
+    /// The party took back its role, with the number of its keys which were about to expire.
+    Resumed {
+        role: Role,
+        persisted: u32,
+    },
+    /// A resume token is unknown or expired, or its room was closed meanwhile.
+    ResumeRefused {
+        room_closed: bool,
+    },
+    /// A resume token belongs to a session which is still connected.
+    ResumeInUse,
+    /// A join was refused, which counts as a failure of the client.
+    JoinRefused(ErrorCode),
+    Knocked,
+    LeftWhileKnocking,
+    /// Caller denied the knocking party, or didn't answer in time.
+    NotAdmitted,
+    /// Caller answered a knock, true if it admitted the other party.
+    AnsweredKnock(bool),
+    /// Callee sent an answer to a knock, which only caller can answer.
+    KnockAnswerDropped,
+    /// A message from the client was dropped, and the client was told why.
+    MessageDropped(ErrorCode),
+    /// A message from the client was pushed to its channel, the event is None if it isn't a protocol message.
+    MessageRelayed {
+        event: Option<Event>,
+        size: usize,
+        latency_ms: u64,
+    },
+    /// The store failed to do something for the session, such as "relay a message".
+    StoreError {
+        action: &'static str,
+        error: String,
+    },
+    /// The client left.
+    Closed,
+}
@@ -0,0 +1,18 @@
+//! Signaling sessions pairing the two parties of a room, whatever hosts them.
+//!
+//! Parties are paired through a store: each party pushes messages to its own channel and polls the other
+//! party's channel, and keys of a party which leaves are kept for a grace period so that it can resume.
+//!
+//! A host, the Cloudflare worker or `hangout-server`, accepts WebSocket connections and runs a [`Session`]
+//! for each of them, plugging in the [`Socket`] and [`Incoming`] messages of the client, a [`Store`] shared by
+//! all sessions and a [`Clock`]. [`Hooks`] let the host limit and observe sessions.
+
+mod host;
+mod memory;
+mod participant;
+pub mod room;
+mod session;
+
+pub use host::{Clock, Hooks, Incoming, Rejection, Report, Socket, Store};
+pub use memory::MemoryStore;
+pub use session::{close_room, room_status, CloseRoomError, Session, MAX_MESSAGE_SIZE};
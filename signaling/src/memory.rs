@@ -0,0 +1,175 @@
+//! An in-memory store with the few Redis commands sessions use, for hosts which run without Redis.
+//!
+//! Keys hold strings or lists and may expire, expired keys are dropped whenever the store is accessed.
+
+use crate::host::Store;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// A store living as long as the process, shared by its clones.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+enum Value {
+    Str(String),
+    /// Pushed at the front, popped at the back, like `LPUSH` and `RPOP`.
+    List(VecDeque<String>),
+}
+
+impl Store for MemoryStore {
+    async fn set_nx(&self, key: &str, value: &str) -> Result<bool, String> {
+        let mut entries = self.entries();
+        if entries.contains_key(key) {
+            return Ok(false);
+        }
+        entries.insert(key.into(), Entry::new(Value::Str(value.into())));
+        Ok(true)
+    }
+
+    async fn set(&self, key: &str, value: &str) -> Result<(), String> {
+        self.entries()
+            .insert(key.into(), Entry::new(Value::Str(value.into())));
+        Ok(())
+    }
+
+    /// Returns the string of a key, None if it doesn't exist or holds a list.
+    async fn get(&self, key: &str) -> Result<Option<String>, String> {
+        match self.entries().get(key) {
+            Some(Entry {
+                value: Value::Str(value),
+                ..
+            }) => Ok(Some(value.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    async fn del(&self, keys: &[&str]) -> Result<(), String> {
+        let mut entries = self.entries();
+        for key in keys {
+            entries.remove(*key);
+        }
+        Ok(())
+    }
+
+    async fn push(&self, key: &str, element: &str) -> Result<(), String> {
+        self.list(key, |list| list.push_front(element.into()));
+        Ok(())
+    }
+
+    async fn pop(&self, key: &str) -> Result<Option<String>, String> {
+        match self.entries().get_mut(key) {
+            Some(Entry {
+                value: Value::List(list),
+                ..
+            }) => Ok(list.pop_back()),
+            _ => Ok(None),
+        }
+    }
+
+    async fn requeue(&self, key: &str, element: &str) -> Result<(), String> {
+        self.list(key, |list| list.push_back(element.into()));
+        Ok(())
+    }
+
+    async fn expire(&self, keys: &[&str], seconds: u32) -> Result<(), String> {
+        let expires_at = Instant::now() + Duration::from_secs(seconds.into());
+        let mut entries = self.entries();
+        for key in keys {
+            if let Some(entry) = entries.get_mut(*key) {
+                entry.expires_at = Some(expires_at);
+            }
+        }
+        Ok(())
+    }
+
+    async fn persist(&self, keys: &[&str]) -> Result<u32, String> {
+        let mut entries = self.entries();
+        let mut persisted = 0;
+        for key in keys {
+            if let Some(entry) = entries.get_mut(*key) {
+                entry.expires_at = None;
+                persisted += 1;
+            }
+        }
+        Ok(persisted)
+    }
+}
+
+impl MemoryStore {
+    /// Changes a list, creating it if needed. A key holding a string is left alone.
+    fn list(&self, key: &str, change: impl FnOnce(&mut VecDeque<String>)) {
+        if let Value::List(list) = &mut self
+            .entries()
+            .entry(key.into())
+            .or_insert_with(|| Entry::new(Value::List(VecDeque::new())))
+            .value
+        {
+            change(list);
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        let mut entries = self.entries.lock().expect("store lock is poisoned");
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires_at.is_none_or(|expires_at| expires_at > now));
+        entries
+    }
+}
+
+impl Entry {
+    fn new(value: Value) -> Entry {
+        Entry {
+            value,
+            expires_at: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStore;
+    use crate::host::Store;
+    use futures::executor::block_on;
+
+    #[test]
+    fn strings() {
+        let store = MemoryStore::default();
+        block_on(async {
+            assert_eq!(store.set_nx("room", "1").await, Ok(true));
+            assert_eq!(store.set_nx("room", "2").await, Ok(false));
+            assert_eq!(store.get("room").await, Ok(Some("1".into())));
+
+            store.expire(&["room", "missing"], 0).await.unwrap();
+            assert_eq!(store.get("room").await, Ok(None));
+            assert_eq!(store.set_nx("room", "3").await, Ok(true));
+            assert_eq!(store.persist(&["room", "missing"]).await, Ok(1));
+        });
+    }
+
+    #[test]
+    fn lists() {
+        let store = MemoryStore::default();
+        block_on(async {
+            store.push("channel", "first").await.unwrap();
+            store.push("channel", "second").await.unwrap();
+            assert_eq!(store.get("channel").await, Ok(None));
+            assert_eq!(store.pop("channel").await, Ok(Some("first".into())));
+            store.requeue("channel", "first").await.unwrap();
+            assert_eq!(store.pop("channel").await, Ok(Some("first".into())));
+            assert_eq!(store.pop("channel").await, Ok(Some("second".into())));
+            assert_eq!(store.pop("channel").await, Ok(None));
+        });
+    }
+}
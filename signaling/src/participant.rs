@@ -0,0 +1,64 @@
+//! What parties tell about themselves when joining, relayed to the other party as is once cleaned up.
+//!
+//! Peer info is self-declared and only meant to label a call, it is never trusted to identify a party.
+
+use protocol::PeerInfo;
+
+/// Maximum length of a display name in characters, longer ones are truncated.
+const MAX_NAME_LEN: usize = 40;
+
+/// Maximum length of a client description in characters, longer ones are truncated.
+const MAX_CLIENT_LEN: usize = 64;
+
+/// Cleans up peer info: texts are truncated, control characters are dropped, blank texts and invalid colors are none.
+pub(crate) fn sanitize(info: &PeerInfo) -> PeerInfo {
+    PeerInfo {
+        name: clean_text(info.name.as_deref(), MAX_NAME_LEN),
+        color: info.color.clone().filter(|color| is_color(color)),
+        client: clean_text(info.client.as_deref(), MAX_CLIENT_LEN),
+    }
+}
+
+fn clean_text(text: Option<&str>, max_len: usize) -> Option<String> {
+    let text: String = text?
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(max_len)
+        .collect();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Tells whether a color is in the form of `#rrggbb`, which is safe to use as is in a page.
+fn is_color(color: &str) -> bool {
+    color.len() == 7
+        && color.starts_with('#')
+        && color[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sanitize;
+    use protocol::PeerInfo;
+
+    #[test]
+    fn sanitized() {
+        let info = sanitize(&PeerInfo {
+            name: Some("  Ada\u{7}  ".into()),
+            color: Some("#3a7bd5".into()),
+            client: Some("peer/0.1.0".into()),
+        });
+        assert_eq!(info.name.as_deref(), Some("Ada"));
+        assert_eq!(info.color.as_deref(), Some("#3a7bd5"));
+        assert_eq!(info.client.as_deref(), Some("peer/0.1.0"));
+
+        let info = sanitize(&PeerInfo {
+            name: Some(" \n ".into()),
+            color: Some("red; background: url(x)".into()),
+            client: Some("x".repeat(100)),
+        });
+        assert_eq!(info.name, None);
+        assert_eq!(info.color, None);
+        assert_eq!(info.client.unwrap().len(), 64);
+    }
+}
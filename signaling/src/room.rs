@@ -0,0 +1,157 @@
+//! Room ids derived from passphrases, so that neither the store nor the logs see raw passphrases.
+//!
+//! A room id is the hex encoded HMAC-SHA256 of the passphrase keyed by the passphrase secret of the host, the
+//! `PASSPHRASE_SECRET` secret of the worker.
+//! Without the secret, room ids can't be brute forced back into passphrases from a leaked store.
+//!
+//! Passphrases picked by users must be strong enough not to be guessed by joining rooms at random,
+//! server generated room codes are an alternative which always is.
+
+use hmac::{Hmac, Mac};
+use protocol::ErrorCode;
+use sha2::Sha256;
+
+/// Minimum length of a passphrase in characters.
+const MIN_PASSPHRASE_LEN: usize = 10;
+
+/// Minimum distinct characters of a passphrase, which rules out repetitions such as "aaaaaaaaaa".
+const MIN_DISTINCT_CHARS: usize = 5;
+
+/// Minimum entropy of a passphrase in bits, estimated from its length and character classes.
+const MIN_ENTROPY_BITS: f64 = 48.0;
+
+/// Number of words of a room code, 8 bits each.
+const ROOM_CODE_WORDS: usize = 6;
+
+/// Words of room codes, short and easy to spell out.
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adult", "agent", "album", "alarm", "alien", "alley", "amber",
+    "angle", "ankle", "apple", "apron", "arena", "armor", "arrow", "atlas", "attic", "audio",
+    "axis", "bacon", "badge", "bagel", "baker", "banjo", "barn", "basil", "basin", "beach",
+    "beard", "berry", "bison", "blade", "blank", "blimp", "bloom", "bonus", "boot", "brick",
+    "bride", "broom", "brush", "buddy", "bugle", "cabin", "cable", "camel", "candy", "canoe",
+    "cargo", "cedar", "chalk", "chess", "chief", "cider", "clam", "cliff", "clock", "cloud",
+    "coast", "cobra", "cocoa", "comet", "coral", "couch", "crab", "crane", "crown", "cube",
+    "curry", "daisy", "dance", "delta", "denim", "desert", "diary", "dingo", "disco", "dock",
+    "donkey", "dough", "dragon", "drum", "eagle", "easel", "echo", "elbow", "elder", "ember",
+    "emu", "engine", "falcon", "fern", "ferry", "fiddle", "field", "fig", "flame", "flute",
+    "focus", "forest", "fossil", "fox", "frost", "fudge", "galaxy", "garden", "garlic", "gecko",
+    "ghost", "giant", "ginger", "glove", "goat", "grape", "gravel", "guitar", "hammer", "harbor",
+    "hazel", "helmet", "heron", "honey", "hornet", "hotel", "igloo", "island", "ivory", "jacket",
+    "jaguar", "jelly", "jewel", "jungle", "kayak", "kettle", "kiwi", "koala", "ladder", "lagoon",
+    "lemon", "lentil", "lilac", "lime", "llama", "locket", "lotus", "lunar", "magnet", "mango",
+    "maple", "marble", "meadow", "melon", "mint", "mirror", "moose", "mosaic", "moss", "motor",
+    "muffin", "nectar", "needle", "nickel", "noodle", "nutmeg", "oasis", "ocean", "olive", "onion",
+    "opal", "orbit", "otter", "oyster", "paddle", "panda", "papaya", "parrot", "peach", "pebble",
+    "pepper", "piano", "pickle", "pigeon", "pillow", "pilot", "pine", "planet", "plum", "pony",
+    "poppy", "potato", "puffin", "quail", "quartz", "quill", "rabbit", "radar", "radio", "raven",
+    "reef", "rhino", "ribbon", "river", "robin", "rocket", "rose", "ruby", "saddle", "salmon",
+    "sandal", "satin", "scarf", "shark", "shell", "sierra", "silver", "sketch", "sloth", "snail",
+    "sonar", "spider", "spruce", "squid", "stamp", "statue", "stone", "sugar", "summit", "swan",
+    "tango", "temple", "tiger", "toast", "tomato", "topaz", "torch", "tower", "tulip", "tundra",
+    "turtle", "valley", "velvet", "violin", "waffle", "walnut", "walrus", "whale", "willow",
+    "window", "wizard", "yacht", "yogurt", "zebra", "zenith", "zipper",
+];
+
+/// Derives the room id of a passphrase.
+pub fn room_id(secret: &str, passphrase: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(passphrase.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Tells whether a string has the shape of a room id, 64 lowercase hex characters.
+pub fn is_room_id(id: &str) -> bool {
+    id.len() == 64
+        && id
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Refuses passphrases which are too short or too simple to resist guessing.
+pub fn check_passphrase(passphrase: &str) -> Result<(), ErrorCode> {
+    let chars: Vec<char> = passphrase.chars().collect();
+    let mut distinct = chars.clone();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if chars.len() < MIN_PASSPHRASE_LEN
+        || distinct.len() < MIN_DISTINCT_CHARS
+        || entropy_bits(&chars) < MIN_ENTROPY_BITS
+    {
+        return Err(ErrorCode::WeakPassphrase);
+    }
+    Ok(())
+}
+
+/// Generates a random room code of words separated by dashes, to be used as a passphrase.
+pub fn generate_code() -> String {
+    let mut bytes = [0u8; ROOM_CODE_WORDS];
+    getrandom::getrandom(&mut bytes).expect("could not generate random bytes");
+    bytes
+        .iter()
+        .map(|&byte| WORDS[byte as usize])
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Estimates entropy as if every character was picked at random from the character classes used.
+fn entropy_bits(chars: &[char]) -> f64 {
+    let mut pool = 0;
+    if chars.iter().any(char::is_ascii_lowercase) {
+        pool += 26;
+    }
+    if chars.iter().any(char::is_ascii_uppercase) {
+        pool += 26;
+    }
+    if chars.iter().any(char::is_ascii_digit) {
+        pool += 10;
+    }
+    // Punctuation, spaces and anything beyond ASCII letters and digits.
+    if chars.iter().any(|c| !c.is_ascii_alphanumeric()) {
+        pool += 33;
+    }
+    chars.len() as f64 * (pool as f64).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_passphrase, generate_code, is_room_id, room_id};
+    use protocol::ErrorCode;
+
+    #[test]
+    fn keyed_hash() {
+        let id = room_id("secret", "passphrase");
+        assert_eq!(
+            id,
+            "b16779ad0580c43cd825363a2b6b28bfab1e034977b8d3fbd1a9d6c484e0f080"
+        );
+        assert_ne!(room_id("another secret", "passphrase"), id);
+        assert_ne!(room_id("secret", "another passphrase"), id);
+
+        assert!(is_room_id(&id));
+        assert!(!is_room_id(&id.to_uppercase()));
+        assert!(!is_room_id("passphrase"));
+    }
+
+    #[test]
+    fn passphrase_strength() {
+        for weak in ["short", "aaaaaaaaaaaaaaaa", "abababababab", "1234567890"] {
+            assert_eq!(check_passphrase(weak), Err(ErrorCode::WeakPassphrase));
+        }
+        for strong in ["correct horse battery", "Tr0ub4dor&3x"] {
+            assert_eq!(check_passphrase(strong), Ok(()));
+        }
+    }
+
+    #[test]
+    fn room_codes() {
+        let code = generate_code();
+        assert_eq!(code.split('-').count(), 6);
+        assert_eq!(check_passphrase(&code), Ok(()));
+    }
+}
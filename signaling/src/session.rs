@@ -0,0 +1,1090 @@
+use crate::{
+    host::{Clock, Hooks, Incoming, Rejection, Report, Socket, Store},
+    participant, room,
+};
+use futures::future::{self, Either};
+use protocol::{ErrorCode, Event, Message, Role, ServerError};
+use std::{
+    pin::pin,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// Largest signaling message accepted in bytes, enough for a sealed session description.
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024;
+
+/// How long in seconds the keys of a disconnected party are kept, so that it can resume the session.
+const RESUME_GRACE_PERIOD: u32 = 60;
+
+/// How long in seconds a session's hold on its resume token outlives its last renewal, should its host
+/// go away without releasing it.
+const LIVE_TTL: u32 = 30;
+
+/// How often a connected session renews the hold on its resume token.
+const LIVE_RENEWAL: Duration = Duration::from_secs(10);
+
+/// How long a knocking party waits for caller's answer before it is turned away.
+const KNOCK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often the other party's channel and the answer to a knock are polled, unless the host says otherwise.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Answers of caller to a knocking party, stored under the admission key of the room.
+const ADMITTED: &str = "admit";
+const DENIED: &str = "deny";
+
+/// A session of a client, from its first message until it leaves.
+pub struct Session<S, W, C, H> {
+    store: S,
+    socket: W,
+    clock: C,
+    hooks: H,
+    /// Key of the keyed hash deriving room ids from passphrases.
+    passphrase_secret: String,
+    poll_interval: Duration,
+}
+
+/// A session registry.
+#[derive(Debug, Clone)]
+struct Registry {
+    room_id: String,
+    /// The key of the party's role in its room.
+    role_key: String,
+    send_channel_key: String,
+    receive_channel_key: String,
+    resume_key: String,
+    /// Key telling that the session of the resume token is connected, so that nobody else resumes it.
+    live_key: String,
+    /// Key through which caller answers a knocking party, caller's only.
+    admission_key: Option<String>,
+    /// What the party told about itself when joining, unknown for a resumed session.
+    info: protocol::PeerInfo,
+}
+
+impl<S: Store, W: Socket, C: Clock, H: Hooks> Session<S, W, C, H> {
+    /// Creates a new session.
+    pub fn new(store: S, socket: W, clock: C, hooks: H, passphrase_secret: String) -> Self {
+        Session {
+            store,
+            socket,
+            clock,
+            hooks,
+            passphrase_secret,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    /// Sets how often the store is polled, every second by default. A store which is cheap to poll makes
+    /// messages go through faster with a shorter interval.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Runs the session until the client leaves.
+    pub async fn run(&self, incoming: &mut impl Incoming) {
+        // Read the first message to get passphrase or resume token.
+        let Some(text) = incoming.next().await else {
+            self.hooks.report(Report::Closed).await;
+            return;
+        };
+        let joined = match serde_json::from_str::<Message>(&text) {
+            Ok(Message {
+                event: Event::Resume,
+                data: token,
+            }) => self.resume(&token).await,
+            _ => self.join(&parse_join(&text), incoming).await,
+        };
+        let Some(registry) = joined else {
+            return;
+        };
+
+        // Once joined the room, subscribe to the other party's channel immediately.
+        let stopped = AtomicBool::new(false);
+        let forward = pin!(async {
+            self.forward(&registry, incoming).await;
+            // The client may resume right away, before subscription notices that it left.
+            self.release_hold(&registry).await;
+            stopped.store(true, Ordering::Relaxed);
+        });
+        let subscribe = pin!(self.subscribe(&registry, &stopped));
+        match future::select(forward, subscribe).await {
+            // Subscription stops on its own once forwarding stops.
+            Either::Left((_, subscribe)) => subscribe.await,
+            // The client would miss the other party's messages, it is told to resume instead.
+            Either::Right(_) => {
+                self.release_hold(&registry).await;
+                self.expire(&registry).await;
+                let error = ServerError {
+                    code: ErrorCode::Unavailable,
+                    retry_after: None,
+                };
+                self.reject(&error, protocol::CLOSE_UNAVAILABLE);
+            }
+        }
+    }
+
+    /// Reports an error to the client, and closes the connection.
+    pub fn reject(&self, error: &ServerError, close_code: u16) {
+        self.send_error(error);
+        self.socket.close(close_code, "refused");
+    }
+
+    /// Forwards client messages to the party's channel.
+    async fn forward(&self, registry: &Registry, incoming: &mut impl Incoming) {
+        while let Some(content) = incoming.next().await {
+            if let Err(error) = self.check_message(registry, &content).await {
+                self.hooks.report(Report::MessageDropped(error.code)).await;
+                self.send_error(&error);
+                continue;
+            }
+
+            let event = serde_json::from_str::<Message>(&content)
+                .ok()
+                .map(|message| message.event);
+            match event {
+                Some(event @ (Event::Admit | Event::Deny)) => {
+                    self.answer_knock(registry, event).await;
+                    continue;
+                }
+                // Only server sends join requests and peer info, parties can't make them up.
+                Some(Event::JoinRequest | Event::PeerInfo) => continue,
+                _ => {}
+            }
+
+            let sent_at = self.clock.now_ms();
+            if let Err(error) = self.store.push(&registry.send_channel_key, &content).await {
+                self.store_error("relay a message", error).await;
+                return;
+            }
+            let report = Report::MessageRelayed {
+                event,
+                size: content.len(),
+                latency_ms: self.clock.now_ms().saturating_sub(sent_at),
+            };
+            self.hooks.report(report).await;
+        }
+        self.hooks.report(Report::Closed).await;
+    }
+
+    /// Joins a session by passphrase, the first one joined is caller, the second one is callee.
+    /// Callee knocks first if caller asked for it, `incoming` tells whether it leaves meanwhile.
+    async fn join(&self, join: &protocol::Join, incoming: &mut impl Incoming) -> Option<Registry> {
+        if let Err(rejection) = self.hooks.check_join().await {
+            self.refuse(rejection).await;
+            return None;
+        }
+        if let Err(code) = room::check_passphrase(&join.passphrase) {
+            self.refuse(Rejection::Refused(code)).await;
+            return None;
+        }
+
+        // The raw passphrase is neither logged nor stored, only the room id derived from it.
+        let room_id = room::room_id(&self.passphrase_secret, &join.passphrase);
+        if let Err(rejection) = self.hooks.check_room(&room_id).await {
+            self.refuse(rejection).await;
+            return None;
+        }
+
+        let role = match self.claim_role(&room_id, join.knock).await {
+            Ok(Some(role)) => role,
+            Ok(None) => {
+                self.refuse(Rejection::Refused(ErrorCode::RoomFull)).await;
+                return None;
+            }
+            Err(error) => {
+                self.store_error("claim a role", error).await;
+                return None;
+            }
+        };
+        self.hooks.report(Report::Joined(role)).await;
+
+        let token = generate_resume_token();
+        let mut registry = Registry::new(&room_id, role, &token);
+        registry.info = participant::sanitize(&join.info);
+        if role == Role::Callee && !self.knock(&room_id, &registry, incoming).await {
+            return None;
+        }
+
+        // Issue a resume token, so that the party can take back its role after a disconnection.
+        let record = Registry::resume_record(&room_id, role);
+        if let Err(error) = self.store.set(&registry.resume_key, &record).await {
+            self.store_error("store resume token", error).await;
+            return None;
+        }
+        if let Err(error) = self.hold(&registry).await {
+            self.store_error("hold resume token", error).await;
+        }
+
+        // The other party learns about this one now, or as soon as it joins.
+        let info = serde_json::to_string(&registry.info).unwrap();
+        let message = message(Event::PeerInfo, info);
+        if let Err(error) = self.store.push(&registry.send_channel_key, &message).await {
+            self.store_error("send peer info", error).await;
+        }
+
+        self.send(Event::Room, room_id);
+        self.send(Event::Passphrase, role_str(role).into());
+        self.send(Event::ResumeToken, token);
+        self.hooks.report(Report::RoleAssigned(role)).await;
+
+        Some(registry)
+    }
+
+    /// Resumes a session of a disconnected party by its resume token.
+    async fn resume(&self, token: &str) -> Option<Registry> {
+        let record = match self.store.get(&Registry::resume_key(token)).await {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                self.hooks
+                    .report(Report::ResumeRefused { room_closed: false })
+                    .await;
+                self.close_invalid_resume_token();
+                return None;
+            }
+            Err(error) => {
+                self.store_error("read resume token", error).await;
+                return None;
+            }
+        };
+        let (room_id, role) = Registry::parse_resume_record(&record)?;
+        if let Err(rejection) = self.hooks.check_room(room_id).await {
+            self.refuse(rejection).await;
+            return None;
+        }
+        let registry = Registry::new(room_id, role, token);
+
+        // The room may have been closed by its creator meanwhile.
+        if let Ok(None) = self.store.get(&registry.role_key).await {
+            self.hooks
+                .report(Report::ResumeRefused { room_closed: true })
+                .await;
+            self.close_invalid_resume_token();
+            return None;
+        }
+
+        // Only one connection at a time holds a role, the one which last held the token may still be around.
+        match self.hold(&registry).await {
+            Ok(true) => {}
+            Ok(false) => {
+                self.hooks.report(Report::ResumeInUse).await;
+                self.socket
+                    .close(protocol::CLOSE_SESSION_IN_USE, "session in use");
+                return None;
+            }
+            Err(error) => {
+                self.store_error("hold resume token", error).await;
+                return None;
+            }
+        }
+
+        // Cancel the expiration set when the party disconnected.
+        let persisted = match self.store.persist(&registry.own_keys()).await {
+            Ok(persisted) => persisted,
+            Err(error) => {
+                self.store_error("persist keys", error).await;
+                0
+            }
+        };
+
+        self.send(Event::Resume, role_str(role).into());
+        self.hooks.report(Report::Resumed { role, persisted }).await;
+
+        Some(registry)
+    }
+
+    /// Makes callee knock if caller of the room asked for it, and waits for caller's answer.
+    /// Returns whether callee may join, a party which is turned away gives its role back.
+    async fn knock(
+        &self,
+        room_id: &str,
+        registry: &Registry,
+        incoming: &mut impl Incoming,
+    ) -> bool {
+        // Caller asks for knocks along with claiming its role, so that no callee gets in before it does.
+        match self
+            .store
+            .get(&Registry::role_key(room_id, Role::Caller))
+            .await
+        {
+            Ok(Some(value)) if Registry::parse_role_value(&value).1 => {}
+            Ok(_) => return true,
+            Err(error) => {
+                self.store_error("read caller's role", error).await;
+                self.release(registry).await;
+                return false;
+            }
+        }
+
+        // An answer left over from a previous knock must not let this party in.
+        let admission_key = Registry::admission_key(room_id);
+        self.store.del(&[&admission_key]).await.ok();
+
+        // The request is the first message caller receives from callee.
+        let info = serde_json::to_string(&registry.info).unwrap();
+        let message = message(Event::JoinRequest, info);
+        if let Err(error) = self.store.push(&registry.send_channel_key, &message).await {
+            self.store_error("send join request", error).await;
+            self.release(registry).await;
+            return false;
+        }
+        self.hooks.report(Report::Knocked).await;
+
+        let answer = pin!(self.wait_answer(&admission_key));
+        // A knocking party has nothing to say until it is admitted, it only may leave.
+        let leaving = pin!(async { while incoming.next().await.is_some() {} });
+        match future::select(answer, leaving).await {
+            Either::Left((true, _)) => true,
+            Either::Left((false, _)) => {
+                self.hooks.report(Report::NotAdmitted).await;
+                self.release(registry).await;
+                let error = ServerError {
+                    code: ErrorCode::JoinDenied,
+                    retry_after: None,
+                };
+                self.reject(&error, protocol::CLOSE_JOIN_REFUSED);
+                false
+            }
+            Either::Right(_) => {
+                self.hooks.report(Report::LeftWhileKnocking).await;
+                self.release(registry).await;
+                false
+            }
+        }
+    }
+
+    /// Polls the admission key of a room for caller's answer, no answer in time is a denial.
+    async fn wait_answer(&self, admission_key: &str) -> bool {
+        let deadline = self.clock.now_ms() + KNOCK_TIMEOUT.as_millis() as u64;
+        loop {
+            if let Ok(Some(answer)) = self.store.get(admission_key).await {
+                self.store.del(&[admission_key]).await.ok();
+                return answer == ADMITTED;
+            }
+            if self.clock.now_ms() >= deadline {
+                return false;
+            }
+            self.clock.sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Passes caller's answer to a knocking party on, through the admission key of the room.
+    async fn answer_knock(&self, registry: &Registry, event: Event) {
+        let Some(admission_key) = &registry.admission_key else {
+            self.hooks.report(Report::KnockAnswerDropped).await;
+            return;
+        };
+        let admitted = event == Event::Admit;
+        self.hooks.report(Report::AnsweredKnock(admitted)).await;
+        let answer = if admitted { ADMITTED } else { DENIED };
+        if let Err(error) = self.store.set(admission_key, answer).await {
+            self.store_error("store answer to a knock", error).await;
+        }
+    }
+
+    /// Marks the resume token of a session as held by this connection, unless another one holds it.
+    /// The hold lapses unless it is renewed, in case the host goes away without releasing it.
+    async fn hold(&self, registry: &Registry) -> Result<bool, String> {
+        if !self.store.set_nx(&registry.live_key, "1").await? {
+            return Ok(false);
+        }
+        self.store.expire(&[&registry.live_key], LIVE_TTL).await?;
+        Ok(true)
+    }
+
+    /// Lets another connection resume the session.
+    async fn release_hold(&self, registry: &Registry) {
+        self.store.del(&[&registry.live_key]).await.ok();
+    }
+
+    /// Keeps the keys of a party which leaves for a grace period instead of deleting them,
+    /// undelivered messages are then delivered when the party resumes the session.
+    async fn expire(&self, registry: &Registry) {
+        if let Err(error) = self
+            .store
+            .expire(&registry.own_keys(), RESUME_GRACE_PERIOD)
+            .await
+        {
+            self.store_error("expire keys", error).await;
+        }
+    }
+
+    /// Gives the role of a party which didn't get to join back, along with anything it sent.
+    async fn release(&self, registry: &Registry) {
+        self.store
+            .del(&[&registry.role_key, &registry.send_channel_key])
+            .await
+            .ok();
+    }
+
+    /// Relays the other party's messages to the client, until forwarding stops or the store fails.
+    async fn subscribe(&self, registry: &Registry, stopped: &AtomicBool) {
+        let mut renewed_at = self.clock.now_ms();
+        loop {
+            if stopped.load(Ordering::Relaxed) {
+                self.expire(registry).await;
+                return;
+            }
+            if self.clock.now_ms().saturating_sub(renewed_at) >= LIVE_RENEWAL.as_millis() as u64 {
+                renewed_at = self.clock.now_ms();
+                if let Err(error) = self.store.expire(&[&registry.live_key], LIVE_TTL).await {
+                    self.store_error("renew hold on resume token", error).await;
+                }
+            }
+
+            match self.store.pop(&registry.receive_channel_key).await {
+                Ok(Some(message)) => {
+                    if self.socket.send(&message).is_err() {
+                        // The WebSocket is closing, put the message back for a resumed session.
+                        self.store
+                            .requeue(&registry.receive_channel_key, &message)
+                            .await
+                            .ok();
+                        self.clock.sleep(self.poll_interval).await;
+                    }
+                }
+                Ok(None) => self.clock.sleep(self.poll_interval).await,
+                Err(error) => {
+                    self.store_error("receive from the other party", error)
+                        .await;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Claims the first free role of a room, caller is free if the room doesn't exist.
+    /// `knock` tells whether the other party must knock, should this party be caller.
+    /// Returns None if both roles are taken.
+    async fn claim_role(&self, room_id: &str, knock: bool) -> Result<Option<Role>, String> {
+        let now = self.clock.now_ms() / 1000;
+        for role in [Role::Caller, Role::Callee] {
+            let value = Registry::role_value(now, role == Role::Caller && knock);
+            if self
+                .store
+                .set_nx(&Registry::role_key(room_id, role), &value)
+                .await?
+            {
+                return Ok(Some(role));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Checks the size of a message from the client, and whatever the host checks.
+    async fn check_message(&self, registry: &Registry, content: &str) -> Result<(), ServerError> {
+        if content.len() > MAX_MESSAGE_SIZE {
+            return Err(ServerError {
+                code: ErrorCode::MessageTooLarge,
+                retry_after: None,
+            });
+        }
+        self.hooks.check_message(&registry.room_id).await
+    }
+
+    /// Turns a join away, a refusal counts as a failure of the client.
+    async fn refuse(&self, rejection: Rejection) {
+        match rejection {
+            Rejection::Refused(code) => {
+                self.hooks.report(Report::JoinRefused(code)).await;
+                let error = ServerError {
+                    code,
+                    retry_after: None,
+                };
+                self.reject(&error, protocol::CLOSE_JOIN_REFUSED);
+            }
+            Rejection::RateLimited(error) => self.reject(&error, protocol::CLOSE_RATE_LIMITED),
+        }
+    }
+
+    fn close_invalid_resume_token(&self) {
+        self.socket
+            .close(protocol::CLOSE_INVALID_RESUME_TOKEN, "invalid resume token");
+    }
+
+    async fn store_error(&self, action: &'static str, error: String) {
+        self.hooks
+            .report(Report::StoreError { action, error })
+            .await;
+    }
+
+    fn send_error(&self, error: &ServerError) {
+        self.send(Event::Error, serde_json::to_string(error).unwrap());
+    }
+
+    fn send(&self, event: Event, data: String) {
+        self.socket.send(&message(event, data)).ok();
+    }
+}
+
+/// Why a room could not be closed.
+#[derive(Debug)]
+pub enum CloseRoomError {
+    /// The resume token is not the creator's of the room.
+    Forbidden,
+    Store(String),
+}
+
+/// Reports the status of a room from the keys of its roles.
+pub async fn room_status(
+    store: &impl Store,
+    room_id: &str,
+) -> Result<protocol::RoomStatus, String> {
+    let mut joined_at = Vec::new();
+    for role in [Role::Caller, Role::Callee] {
+        // Keys of rooms created before join times were stored hold an empty string.
+        if let Some(value) = store.get(&Registry::role_key(room_id, role)).await? {
+            joined_at.push(Registry::parse_role_value(&value).0);
+        }
+    }
+    let state = match joined_at.len() {
+        0 => protocol::RoomState::Empty,
+        1 => protocol::RoomState::Waiting,
+        _ => protocol::RoomState::Full,
+    };
+    Ok(protocol::RoomStatus {
+        state,
+        participants: joined_at.len() as u8,
+        created_at: joined_at.into_iter().flatten().min(),
+    })
+}
+
+/// Closes a room on behalf of its creator, who proves it with its resume token.
+/// Parties which are still connected are left alone, but can't resume anymore.
+pub async fn close_room(
+    store: &impl Store,
+    room_id: &str,
+    resume_token: &str,
+) -> Result<(), CloseRoomError> {
+    let resume_key = Registry::resume_key(resume_token);
+    let record = match store.get(&resume_key).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return Err(CloseRoomError::Forbidden),
+        Err(error) => return Err(CloseRoomError::Store(error)),
+    };
+    if Registry::parse_resume_record(&record) != Some((room_id, Role::Caller)) {
+        return Err(CloseRoomError::Forbidden);
+    }
+
+    let keys = [
+        Registry::role_key(room_id, Role::Caller),
+        Registry::role_key(room_id, Role::Callee),
+        Registry::caller_channel_key(room_id),
+        Registry::callee_channel_key(room_id),
+        Registry::admission_key(room_id),
+        resume_key,
+    ];
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    store.del(&keys).await.map_err(CloseRoomError::Store)
+}
+
+/// Reads a join message, older peers send a bare passphrase instead.
+fn parse_join(text: &str) -> protocol::Join {
+    serde_json::from_str::<Message>(text)
+        .ok()
+        .filter(|message| message.event == Event::Join)
+        .and_then(|message| serde_json::from_str(&message.data).ok())
+        .unwrap_or_else(|| protocol::Join {
+            passphrase: text.into(),
+            info: protocol::PeerInfo::default(),
+            knock: false,
+        })
+}
+
+fn message(event: Event, data: String) -> String {
+    serde_json::to_string(&Message { event, data }).unwrap()
+}
+
+/// Generates a random resume token of 32 hex characters.
+fn generate_resume_token() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("could not generate random bytes");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Role representation on wire, "1" for caller and "0" for callee.
+fn role_str(role: Role) -> &'static str {
+    match role {
+        Role::Caller => "1",
+        Role::Callee => "0",
+    }
+}
+
+fn parse_role(s: &str) -> Option<Role> {
+    match s {
+        "1" => Some(Role::Caller),
+        "0" => Some(Role::Callee),
+        _ => None,
+    }
+}
+
+impl Registry {
+    fn new(room_id: &str, role: Role, token: &str) -> Registry {
+        // A peer sends on its own channel, receives on the other party's channel.
+        let (send_channel_key, receive_channel_key) = match role {
+            Role::Caller => (
+                Self::caller_channel_key(room_id),
+                Self::callee_channel_key(room_id),
+            ),
+            Role::Callee => (
+                Self::callee_channel_key(room_id),
+                Self::caller_channel_key(room_id),
+            ),
+        };
+        let admission_key = match role {
+            Role::Caller => Some(Self::admission_key(room_id)),
+            Role::Callee => None,
+        };
+        Registry {
+            room_id: room_id.into(),
+            role_key: Self::role_key(room_id, role),
+            send_channel_key,
+            receive_channel_key,
+            resume_key: Self::resume_key(token),
+            live_key: Self::live_key(token),
+            admission_key,
+            info: protocol::PeerInfo::default(),
+        }
+    }
+
+    /// Keys owned by the party, they live as long as the party is connected or can resume.
+    fn own_keys(&self) -> Vec<&str> {
+        [
+            Some(&self.role_key),
+            Some(&self.send_channel_key),
+            Some(&self.resume_key),
+            self.admission_key.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect()
+    }
+
+    /// The key of a role in a room, the room key itself is caller's.
+    fn role_key(room_id: &str, role: Role) -> String {
+        match role {
+            Role::Caller => format!("room:{}", room_id),
+            Role::Callee => format!("room:{}:callee", room_id),
+        }
+    }
+
+    /// The value stored under a role key, the time the party joined, followed by `:knock` if caller asked
+    /// callee to knock.
+    fn role_value(joined_at: u64, knock: bool) -> String {
+        match knock {
+            true => format!("{}:knock", joined_at),
+            false => joined_at.to_string(),
+        }
+    }
+
+    /// Reads the time a party joined and whether callee must knock from the value of a role key.
+    fn parse_role_value(value: &str) -> (Option<u64>, bool) {
+        match value.strip_suffix(":knock") {
+            Some(joined_at) => (joined_at.parse().ok(), true),
+            None => (value.parse().ok(), false),
+        }
+    }
+
+    fn admission_key(room_id: &str) -> String {
+        format!("room:{}:admission", room_id)
+    }
+
+    fn caller_channel_key(room_id: &str) -> String {
+        format!("channel:{}:caller", room_id)
+    }
+
+    fn callee_channel_key(room_id: &str) -> String {
+        format!("channel:{}:callee", room_id)
+    }
+
+    fn resume_key(token: &str) -> String {
+        format!("resume:{}", token)
+    }
+
+    fn live_key(token: &str) -> String {
+        format!("resume:{}:live", token)
+    }
+
+    /// The value stored under a resume key, in the form of `<role>:<room id>`.
+    fn resume_record(room_id: &str, role: Role) -> String {
+        format!("{}:{}", role_str(role), room_id)
+    }
+
+    fn parse_resume_record(record: &str) -> Option<(&str, Role)> {
+        let (role, room_id) = record.split_once(':')?;
+        Some((room_id, parse_role(role)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Registry, Session};
+    use crate::{room, Clock, Incoming, MemoryStore, Socket, Store};
+    use futures::{
+        channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+        executor::block_on,
+        future, StreamExt,
+    };
+    use protocol::{ErrorCode, Event, Join, Message, PeerInfo, Role, ServerError};
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+        task::Poll,
+        time::Duration,
+    };
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// A client socket recording what it is sent.
+    #[derive(Debug, Clone, Default)]
+    struct Client {
+        sent: Rc<RefCell<Vec<Message>>>,
+        closed: Rc<Cell<Option<u16>>>,
+    }
+
+    impl Socket for Client {
+        fn send(&self, text: &str) -> Result<(), String> {
+            if self.closed.get().is_some() {
+                return Err("closed".into());
+            }
+            self.sent
+                .borrow_mut()
+                .push(serde_json::from_str(text).unwrap());
+            Ok(())
+        }
+
+        fn close(&self, code: u16, _reason: &str) {
+            self.closed.set(Some(code));
+        }
+    }
+
+    impl Client {
+        fn received(&self, event: Event) -> Option<String> {
+            self.sent
+                .borrow()
+                .iter()
+                .find(|message| message.event == event)
+                .map(|message| message.data.clone())
+        }
+    }
+
+    struct Messages(UnboundedReceiver<String>);
+
+    impl Incoming for Messages {
+        async fn next(&mut self) -> Option<String> {
+            self.0.next().await
+        }
+    }
+
+    /// A clock whose time only goes by when sessions sleep, sleeping lets other sessions run.
+    #[derive(Debug, Clone, Default)]
+    struct TestClock(Rc<Cell<u64>>);
+
+    impl Clock for TestClock {
+        fn now_ms(&self) -> u64 {
+            self.0.get()
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration.as_millis() as u64);
+            yield_now().await;
+        }
+    }
+
+    async fn yield_now() {
+        let mut yielded = false;
+        future::poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// A store which can't pop from lists, as if it went down once a party joined.
+    #[derive(Debug, Clone, Default)]
+    struct FailingPops(MemoryStore);
+
+    impl Store for FailingPops {
+        async fn set_nx(&self, key: &str, value: &str) -> Result<bool, String> {
+            self.0.set_nx(key, value).await
+        }
+
+        async fn set(&self, key: &str, value: &str) -> Result<(), String> {
+            self.0.set(key, value).await
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<String>, String> {
+            self.0.get(key).await
+        }
+
+        async fn del(&self, keys: &[&str]) -> Result<(), String> {
+            self.0.del(keys).await
+        }
+
+        async fn push(&self, key: &str, element: &str) -> Result<(), String> {
+            self.0.push(key, element).await
+        }
+
+        async fn pop(&self, _key: &str) -> Result<Option<String>, String> {
+            Err("connection refused".into())
+        }
+
+        async fn requeue(&self, key: &str, element: &str) -> Result<(), String> {
+            self.0.requeue(key, element).await
+        }
+
+        async fn expire(&self, keys: &[&str], seconds: u32) -> Result<(), String> {
+            self.0.expire(keys, seconds).await
+        }
+
+        async fn persist(&self, keys: &[&str]) -> Result<u32, String> {
+            self.0.persist(keys).await
+        }
+    }
+
+    type TestSession = Session<MemoryStore, Client, TestClock, ()>;
+
+    /// A session of a client which sends `messages` first.
+    fn connect(
+        store: &MemoryStore,
+        messages: &[String],
+    ) -> (TestSession, Client, UnboundedSender<String>, Messages) {
+        let client = Client::default();
+        let session = Session::new(
+            store.clone(),
+            client.clone(),
+            TestClock::default(),
+            (),
+            "secret".into(),
+        );
+        let (sender, receiver) = mpsc::unbounded();
+        for message in messages {
+            sender.unbounded_send(message.clone()).unwrap();
+        }
+        (session, client, sender, Messages(receiver))
+    }
+
+    fn join(name: &str, knock: bool) -> String {
+        let join = Join {
+            passphrase: PASSPHRASE.into(),
+            info: PeerInfo {
+                name: Some(name.into()),
+                ..PeerInfo::default()
+            },
+            knock,
+        };
+        message(Event::Join, serde_json::to_string(&join).unwrap())
+    }
+
+    fn message(event: Event, data: String) -> String {
+        serde_json::to_string(&Message { event, data }).unwrap()
+    }
+
+    /// Runs a session of a client which leaves after sending `messages`.
+    fn run(store: &MemoryStore, messages: &[String]) -> Client {
+        let (session, client, sender, mut incoming) = connect(store, messages);
+        drop(sender);
+        block_on(session.run(&mut incoming));
+        client
+    }
+
+    /// Yields until a client is sent an event.
+    async fn until_received(client: &Client, event: Event) -> String {
+        loop {
+            if let Some(data) = client.received(event) {
+                return data;
+            }
+            yield_now().await;
+        }
+    }
+
+    #[test]
+    fn roles() {
+        let store = MemoryStore::default();
+        let caller = run(&store, &[join("Ada", false)]);
+        assert_eq!(caller.received(Event::Passphrase).as_deref(), Some("1"));
+        assert!(caller.received(Event::ResumeToken).is_some());
+
+        // Keys of a party which left are kept, so the room has a caller still.
+        let callee = run(&store, &[PASSPHRASE.into()]);
+        assert_eq!(callee.received(Event::Passphrase).as_deref(), Some("0"));
+        assert!(callee.received(Event::Room).is_some());
+        assert_eq!(callee.received(Event::Room), caller.received(Event::Room));
+
+        let third = run(&store, &[join("Eve", false)]);
+        let error: ServerError =
+            serde_json::from_str(&third.received(Event::Error).unwrap()).unwrap();
+        assert_eq!(error.code, ErrorCode::RoomFull);
+        assert_eq!(third.closed.get(), Some(protocol::CLOSE_JOIN_REFUSED));
+    }
+
+    #[test]
+    fn relay() {
+        let store = MemoryStore::default();
+        let offer = message(Event::Offer, "sdp".into());
+        let (caller, caller_client, caller_sender, mut caller_incoming) =
+            connect(&store, &[join("Ada", false), offer]);
+        let (callee, callee_client, callee_sender, mut callee_incoming) =
+            connect(&store, &[join("Grace", false)]);
+
+        block_on(async {
+            let leave = async {
+                until_received(&callee_client, Event::Offer).await;
+                until_received(&caller_client, Event::PeerInfo).await;
+                drop(caller_sender);
+                drop(callee_sender);
+            };
+            futures::join!(
+                caller.run(&mut caller_incoming),
+                callee.run(&mut callee_incoming),
+                leave
+            );
+        });
+
+        assert_eq!(callee_client.received(Event::Offer).as_deref(), Some("sdp"));
+        let info: PeerInfo =
+            serde_json::from_str(&callee_client.received(Event::PeerInfo).unwrap()).unwrap();
+        assert_eq!(info.name.as_deref(), Some("Ada"));
+        let info: PeerInfo =
+            serde_json::from_str(&caller_client.received(Event::PeerInfo).unwrap()).unwrap();
+        assert_eq!(info.name.as_deref(), Some("Grace"));
+    }
+
+    #[test]
+    fn knock_denied() {
+        let store = MemoryStore::default();
+        let (caller, caller_client, caller_sender, mut caller_incoming) =
+            connect(&store, &[join("Ada", true)]);
+        let (callee, callee_client, callee_sender, mut callee_incoming) =
+            connect(&store, &[join("Mallory", false)]);
+
+        block_on(async {
+            let deny = async {
+                let request = until_received(&caller_client, Event::JoinRequest).await;
+                let info: PeerInfo = serde_json::from_str(&request).unwrap();
+                assert_eq!(info.name.as_deref(), Some("Mallory"));
+                caller_sender
+                    .unbounded_send(message(Event::Deny, String::new()))
+                    .unwrap();
+                until_received(&callee_client, Event::Error).await;
+                drop(caller_sender);
+                drop(callee_sender);
+            };
+            futures::join!(
+                caller.run(&mut caller_incoming),
+                callee.run(&mut callee_incoming),
+                deny
+            );
+        });
+
+        assert_eq!(callee_client.received(Event::Passphrase), None);
+        let error: ServerError =
+            serde_json::from_str(&callee_client.received(Event::Error).unwrap()).unwrap();
+        assert_eq!(error.code, ErrorCode::JoinDenied);
+        assert_eq!(
+            callee_client.closed.get(),
+            Some(protocol::CLOSE_JOIN_REFUSED)
+        );
+        // The role of the party which was turned away is free again.
+        let room_id = room::room_id("secret", PASSPHRASE);
+        let callee_key = Registry::role_key(&room_id, Role::Callee);
+        assert_eq!(block_on(store.get(&callee_key)), Ok(None));
+    }
+
+    #[test]
+    fn knock_asked_along_with_role() {
+        // Caller has claimed its role and nothing else yet when callee joins.
+        let store = MemoryStore::default();
+        let room_id = room::room_id("secret", PASSPHRASE);
+        let caller_key = Registry::role_key(&room_id, Role::Caller);
+        block_on(store.set(&caller_key, &Registry::role_value(0, true))).unwrap();
+
+        // Nobody answers, so callee is turned away once its knock times out.
+        let (callee, callee_client, _callee_sender, mut callee_incoming) =
+            connect(&store, &[join("Mallory", false)]);
+        block_on(callee.run(&mut callee_incoming));
+
+        assert_eq!(callee_client.received(Event::Passphrase), None);
+        let error: ServerError =
+            serde_json::from_str(&callee_client.received(Event::Error).unwrap()).unwrap();
+        assert_eq!(error.code, ErrorCode::JoinDenied);
+    }
+
+    #[test]
+    fn store_failure_ends_session() {
+        let client = Client::default();
+        let session = Session::new(
+            FailingPops::default(),
+            client.clone(),
+            TestClock::default(),
+            (),
+            "secret".into(),
+        );
+        // The client stays connected, yet its session ends.
+        let (sender, receiver) = mpsc::unbounded();
+        sender.unbounded_send(join("Ada", false)).unwrap();
+        block_on(session.run(&mut Messages(receiver)));
+
+        assert!(client.received(Event::Passphrase).is_some());
+        let error: ServerError =
+            serde_json::from_str(&client.received(Event::Error).unwrap()).unwrap();
+        assert_eq!(error.code, ErrorCode::Unavailable);
+        assert_eq!(client.closed.get(), Some(protocol::CLOSE_UNAVAILABLE));
+    }
+
+    #[test]
+    fn resume_in_use() {
+        let store = MemoryStore::default();
+        let (caller, caller_client, caller_sender, mut caller_incoming) =
+            connect(&store, &[join("Ada", false)]);
+
+        let token = block_on(async {
+            let resume = async {
+                let token = until_received(&caller_client, Event::ResumeToken).await;
+                // The caller is still connected, its session can't be taken over.
+                let (other, other_client, other_sender, mut other_incoming) =
+                    connect(&store, &[message(Event::Resume, token.clone())]);
+                drop(other_sender);
+                other.run(&mut other_incoming).await;
+                assert_eq!(other_client.received(Event::Resume), None);
+                assert_eq!(
+                    other_client.closed.get(),
+                    Some(protocol::CLOSE_SESSION_IN_USE)
+                );
+                drop(caller_sender);
+                token
+            };
+            futures::join!(caller.run(&mut caller_incoming), resume).1
+        });
+
+        // Once the caller left, its session is resumed.
+        let resumed = run(&store, &[message(Event::Resume, token)]);
+        assert_eq!(resumed.received(Event::Resume).as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn invalid_resume_token() {
+        let store = MemoryStore::default();
+        let client = run(&store, &[message(Event::Resume, "unknown".into())]);
+        assert_eq!(
+            client.closed.get(),
+            Some(protocol::CLOSE_INVALID_RESUME_TOKEN)
+        );
+    }
+
+    #[test]
+    fn resume_record() {
+        let record = Registry::resume_record("with:colon", Role::Callee);
+        assert_eq!(
+            Registry::parse_resume_record(&record),
+            Some(("with:colon", Role::Callee))
+        );
+        assert_eq!(Registry::parse_resume_record("2:passphrase"), None);
+        assert_eq!(Registry::parse_resume_record("passphrase"), None);
+    }
+}
@@ -0,0 +1,364 @@
+//! Static assets of the worker site, looked up through the manifest generated by wrangler.
+//!
+//! Wrangler uploads each file of the site bucket to the `__STATIC_CONTENT` KV namespace under a key with
+//! a hash of its content, such as `pkg/peer.4d3f2a1b9c.js`, and maps paths to keys in the
+//! `__STATIC_CONTENT_MANIFEST` module. Assets requested by path are revalidated with their ETag on every use,
+//! assets requested by key never change and are cached for good.
+//!
+//! The peer's script and WASM module are compressed with Brotli and gzip at build time, `just build-peer`
+//! writes `peer.js.br`, `peer.js.gz` and so on next to them, and a variant accepted by the client is served
+//! in place of the file. Variants have keys of their own, so that their ETags differ from the file's.
+
+use std::collections::HashMap;
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+use worker::{js_sys::Reflect, worker_sys, Response};
+
+#[wasm_bindgen(module = "__STATIC_CONTENT_MANIFEST")]
+extern "C" {
+    #[wasm_bindgen(js_name = "default")]
+    static MANIFEST: String;
+}
+
+thread_local! {
+    static ASSETS: Manifest = Manifest::parse(&MANIFEST);
+}
+
+/// Paths of assets mapped to their KV keys.
+#[derive(Debug, Default)]
+pub(crate) struct Manifest {
+    keys: HashMap<String, String>,
+}
+
+/// An asset found in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Asset {
+    /// Path of the asset in the site.
+    path: String,
+    /// KV key of the body, the key of a precompressed variant if one is served.
+    pub(crate) key: String,
+    /// Content coding of the body, if a precompressed variant is served.
+    pub(crate) encoding: Option<Encoding>,
+    /// Whether the asset is requested by its hashed key, rather than by path.
+    immutable: bool,
+}
+
+/// Looks up the asset of a request path in the site manifest, picking a precompressed variant accepted
+/// according to an `Accept-Encoding` header.
+pub(crate) fn lookup(path: &str, accept_encoding: &str) -> Option<Asset> {
+    ASSETS.with(|assets| assets.lookup(path, accept_encoding))
+}
+
+/// Converts a response for the runtime. The runtime compresses bodies according to their `Content-Encoding`
+/// header, unless told with `encodeBody: "manual"` that they are compressed already, which the `worker`
+/// crate doesn't expose: responses with that header, precompressed assets, are rebuilt with it.
+pub(crate) fn into_edge_response(response: Response) -> worker_sys::Response {
+    let precompressed = response.headers().has("Content-Encoding").unwrap_or(false);
+    let response = worker_sys::Response::from(response);
+    if !precompressed {
+        return response;
+    }
+    let mut init = worker_sys::ResponseInit::new();
+    init.status(response.status()).headers(&response.headers());
+    Reflect::set(&init, &"encodeBody".into(), &JsValue::from("manual")).ok();
+    worker_sys::Response::new_with_opt_stream_and_init(response.body(), &init).unwrap_or(response)
+}
+
+/// A content coding of compressed assets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+/// Picks the encoding of a response from an `Accept-Encoding` header, Brotli is preferred at equal quality.
+pub(crate) fn negotiate_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';').map(|param| param.trim());
+        let encoding = match params.next().unwrap_or_default() {
+            "br" => Encoding::Brotli,
+            "gzip" => Encoding::Gzip,
+            _ => continue,
+        };
+        let quality: f32 = match params.find_map(|param| param.strip_prefix("q=")) {
+            Some(quality) => quality.parse().unwrap_or(0.0),
+            None => 1.0,
+        };
+        let is_better = match best {
+            Some((best_encoding, best_quality)) => {
+                quality > best_quality
+                    || (quality == best_quality && best_encoding == Encoding::Gzip)
+            }
+            None => true,
+        };
+        if quality > 0.0 && is_better {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+impl Encoding {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of the variants compressed with this encoding.
+    fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+impl Manifest {
+    fn parse(manifest: &str) -> Manifest {
+        Manifest {
+            keys: serde_json::from_str(manifest).unwrap_or_default(),
+        }
+    }
+
+    fn lookup(&self, path: &str, accept_encoding: &str) -> Option<Asset> {
+        let mut path = path.trim_start_matches('/').to_string();
+        if path.is_empty() || path.ends_with('/') {
+            path.push_str("index.html");
+        }
+        let (path, key, immutable) = match self.keys.get_key_value(&path) {
+            Some((path, key)) => (path, key, false),
+            None => {
+                let (path, key) = self.keys.iter().find(|&(_, key)| *key == path)?;
+                (path, key, true)
+            }
+        };
+        let mut asset = Asset {
+            path: path.clone(),
+            key: key.clone(),
+            encoding: None,
+            immutable,
+        };
+        if !asset.is_compressible() {
+            return Some(asset);
+        }
+        let encoding = negotiate_encoding(accept_encoding);
+        let variant = encoding.and_then(|encoding| {
+            self.keys
+                .get(&format!("{}.{}", path, encoding.extension()))
+                .map(|key| (encoding, key))
+        });
+        if let Some((encoding, key)) = variant {
+            asset.key = key.clone();
+            asset.encoding = Some(encoding);
+        }
+        Some(asset)
+    }
+}
+
+impl Asset {
+    pub(crate) fn content_type(&self) -> &'static str {
+        let extension = self.path.rsplit_once('.').map(|(_, extension)| extension);
+        match extension.unwrap_or_default() {
+            "html" => "text/html; charset=utf-8",
+            "js" | "mjs" => "application/javascript",
+            "wasm" => "application/wasm",
+            "css" => "text/css; charset=utf-8",
+            "json" => "application/json",
+            "svg" => "image/svg+xml",
+            "png" => "image/png",
+            "ico" => "image/x-icon",
+            "txt" => "text/plain; charset=utf-8",
+            _ => "application/octet-stream",
+        }
+    }
+
+    /// Tells whether the asset is worth compressing, images other than SVG already are compressed.
+    pub(crate) fn is_compressible(&self) -> bool {
+        let content_type = self.content_type();
+        content_type.starts_with("text/")
+            || [
+                "application/javascript",
+                "application/wasm",
+                "application/json",
+                "image/svg+xml",
+            ]
+            .contains(&content_type)
+    }
+
+    pub(crate) fn cache_control(&self) -> &'static str {
+        if self.immutable {
+            "public, max-age=31536000, immutable"
+        } else {
+            "public, max-age=0, must-revalidate"
+        }
+    }
+
+    /// A strong ETag, keys change along with the content of assets and differ between variants.
+    pub(crate) fn etag(&self) -> String {
+        format!("\"{}\"", self.key)
+    }
+
+    /// Tells whether the client already has this asset, according to its `If-None-Match` header.
+    pub(crate) fn is_not_modified(&self, if_none_match: &str) -> bool {
+        let etag = self.etag();
+        if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate_encoding, Encoding, Manifest};
+
+    const MANIFEST: &str = r#"{
+        "index.html": "index.1a2b3c.html",
+        "pkg/peer.js": "pkg/peer.4d5e6f.js",
+        "pkg/peer.js.br": "pkg/peer.js.0a1b2c.br",
+        "pkg/peer.js.gz": "pkg/peer.js.3d4e5f.gz",
+        "pkg/peer_bg.wasm": "pkg/peer_bg.7a8b9c.wasm",
+        "pkg/peer_bg.wasm.br": "pkg/peer_bg.wasm.6a7b8c.br"
+    }"#;
+
+    #[test]
+    fn lookup() {
+        let manifest = Manifest::parse(MANIFEST);
+
+        let index = manifest.lookup("/", "").unwrap();
+        assert_eq!(index.key, "index.1a2b3c.html");
+        assert_eq!(index.content_type(), "text/html; charset=utf-8");
+        assert_eq!(index.cache_control(), "public, max-age=0, must-revalidate");
+
+        let wasm = manifest.lookup("/pkg/peer_bg.wasm", "").unwrap();
+        assert_eq!(wasm.content_type(), "application/wasm");
+
+        let hashed = manifest.lookup("/pkg/peer.4d5e6f.js", "").unwrap();
+        assert_eq!(hashed.content_type(), "application/javascript");
+        assert_eq!(
+            hashed.cache_control(),
+            "public, max-age=31536000, immutable"
+        );
+
+        assert_eq!(manifest.lookup("/pkg/", ""), None);
+        assert_eq!(manifest.lookup("/peer.js", ""), None);
+    }
+
+    #[test]
+    fn etags() {
+        let asset = Manifest::parse(MANIFEST)
+            .lookup("/pkg/peer.js", "")
+            .unwrap();
+        assert_eq!(asset.etag(), "\"pkg/peer.4d5e6f.js\"");
+        assert!(asset.is_not_modified("\"pkg/peer.4d5e6f.js\""));
+        assert!(asset.is_not_modified("\"other\", W/\"pkg/peer.4d5e6f.js\""));
+        assert!(asset.is_not_modified("*"));
+        assert!(!asset.is_not_modified("\"pkg/peer.000000.js\""));
+        assert!(!asset.is_not_modified("\"pkg/peer.js.0a1b2c.br\""));
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(
+            negotiate_encoding("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate_encoding("br;q=0.5, gzip;q=0.8"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate_encoding("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_encoding("identity, deflate"), None);
+        assert_eq!(negotiate_encoding(""), None);
+
+        let manifest = Manifest::parse(MANIFEST);
+        let brotli = manifest.lookup("/pkg/peer.js", "gzip, br").unwrap();
+        assert_eq!(brotli.key, "pkg/peer.js.0a1b2c.br");
+        assert_eq!(brotli.encoding, Some(Encoding::Brotli));
+        assert_eq!(brotli.content_type(), "application/javascript");
+        assert_eq!(brotli.etag(), "\"pkg/peer.js.0a1b2c.br\"");
+
+        // Requested by key, the variants of its path are served.
+        let gzip = manifest.lookup("/pkg/peer.4d5e6f.js", "gzip").unwrap();
+        assert_eq!(gzip.key, "pkg/peer.js.3d4e5f.gz");
+        assert_eq!(gzip.encoding, Some(Encoding::Gzip));
+        assert_eq!(gzip.cache_control(), "public, max-age=31536000, immutable");
+
+        // Without a variant of the accepted encoding, the file is served as is.
+        let wasm = manifest.lookup("/pkg/peer_bg.wasm", "gzip").unwrap();
+        assert!(wasm.is_compressible());
+        assert_eq!(wasm.key, "pkg/peer_bg.7a8b9c.wasm");
+        assert_eq!(wasm.encoding, None);
+        let index = manifest.lookup("/", "br").unwrap();
+        assert_eq!(index.encoding, None);
+    }
+
+    /// Files `just build-peer` leaves in the site bucket.
+    const BUILT_FILES: &[&str] = &[
+        "index.html",
+        "pkg/package.json",
+        "pkg/peer.d.ts",
+        "pkg/peer.js",
+        "pkg/peer.js.br",
+        "pkg/peer.js.gz",
+        "pkg/peer_bg.wasm",
+        "pkg/peer_bg.wasm.br",
+        "pkg/peer_bg.wasm.gz",
+    ];
+
+    /// The manifest wrangler generates from the files matching the `include` patterns of `wrangler.toml`.
+    fn deployed_manifest() -> Manifest {
+        let config = include_str!("../wrangler.toml");
+        let include = config
+            .lines()
+            .find_map(|line| line.strip_prefix("include = "))
+            .unwrap();
+        let patterns: Vec<String> = serde_json::from_str(include).unwrap();
+        let keys = BUILT_FILES
+            .iter()
+            .filter(|path| patterns.iter().any(|pattern| matches(pattern, path)))
+            .map(|path| {
+                // Wrangler puts a hash of the content before the extension.
+                let (stem, extension) = path.rsplit_once('.').unwrap();
+                (
+                    path.to_string(),
+                    format!("{}.0a1b2c3d4e.{}", stem, extension),
+                )
+            })
+            .collect();
+        Manifest { keys }
+    }
+
+    /// Matches a path against a pattern with at most one `*`, which doesn't match across directories.
+    fn matches(pattern: &str, path: &str) -> bool {
+        match pattern.split_once('*') {
+            Some((prefix, suffix)) => path
+                .strip_prefix(prefix)
+                .and_then(|rest| rest.strip_suffix(suffix))
+                .is_some_and(|matched| !matched.contains('/')),
+            None => pattern == path,
+        }
+    }
+
+    #[test]
+    fn deployed_variants() {
+        let manifest = deployed_manifest();
+        for path in ["/pkg/peer.js", "/pkg/peer_bg.wasm"] {
+            for (accept_encoding, encoding) in [("br", Encoding::Brotli), ("gzip", Encoding::Gzip)]
+            {
+                let asset = manifest.lookup(path, accept_encoding).unwrap();
+                assert_eq!(
+                    asset.encoding,
+                    Some(encoding),
+                    "{} in {}",
+                    path,
+                    accept_encoding
+                );
+            }
+        }
+        assert!(manifest.lookup("/", "").is_some());
+        assert_eq!(manifest.lookup("/pkg/package.json", ""), None);
+    }
+}
@@ -0,0 +1,207 @@
+//! Optional authentication of signaling, enabled by setting the `AUTH_SECRET` secret of the worker.
+//!
+//! Clients present a JWT signed with HS256 by the secret, either in the `token` query parameter, since
+//! browsers can't set headers on WebSocket requests, or in an `Authorization: Bearer` header.
+//! A token with a `room` claim is an invite: it only lets its holder join that room.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use subtle::ConstantTimeEq;
+
+/// How long invites issued along with room codes are valid, in seconds.
+pub(crate) const INVITE_TTL: u64 = 24 * 60 * 60;
+
+/// Claims of a verified token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Claims {
+    /// Who the token is issued to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sub: Option<String>,
+    /// Unix timestamp after which the token is refused.
+    pub(crate) exp: u64,
+    /// Id of the only room an invite lets its holder join.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) room: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    alg: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AuthError {
+    /// No token is presented.
+    Missing,
+    /// The token is not a JWT.
+    Malformed,
+    /// The token is signed with another algorithm than HS256.
+    UnsupportedAlgorithm,
+    InvalidSignature,
+    Expired,
+}
+
+impl Claims {
+    /// Tells whether the token lets its holder join a room.
+    pub(crate) fn allows_room(&self, room_id: &str) -> bool {
+        self.room.as_deref().is_none_or(|room| room == room_id)
+    }
+}
+
+/// Signs claims into a JWT.
+pub(crate) fn sign(claims: &Claims, secret: &str) -> String {
+    let header = Header {
+        alg: "HS256".into(),
+    };
+    let signing_input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap()),
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap())
+    );
+    let signature = mac(secret, &signing_input).finalize().into_bytes();
+    format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// Verifies a JWT and returns its claims, `now` is a unix timestamp.
+pub(crate) fn verify(token: &str, secret: &str, now: u64) -> Result<Claims, AuthError> {
+    let (signing_input, signature) = token.rsplit_once('.').ok_or(AuthError::Malformed)?;
+    let (header, claims) = signing_input.split_once('.').ok_or(AuthError::Malformed)?;
+
+    let header: Header = decode_json(header)?;
+    if header.alg != "HS256" {
+        return Err(AuthError::UnsupportedAlgorithm);
+    }
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| AuthError::Malformed)?;
+    // Compares in constant time.
+    mac(secret, signing_input)
+        .verify_slice(&signature)
+        .map_err(|_| AuthError::InvalidSignature)?;
+
+    let claims: Claims = decode_json(claims)?;
+    if claims.exp <= now {
+        return Err(AuthError::Expired);
+    }
+    Ok(claims)
+}
+
+/// Tells whether an `Authorization` header presents a static bearer token, such as `METRICS_TOKEN`.
+pub(crate) fn is_bearer(authorization: &str, token: &str) -> bool {
+    let Some(presented) = authorization.strip_prefix("Bearer ") else {
+        return false;
+    };
+    // Compares in constant time, so that timing tells nothing of how much of a guess is right.
+    presented.as_bytes().ct_eq(token.as_bytes()).into()
+}
+
+fn mac(secret: &str, signing_input: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(signing_input.as_bytes());
+    mac
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, AuthError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| AuthError::Malformed)?;
+    serde_json::from_slice(&bytes).map_err(|_| AuthError::Malformed)
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing token"),
+            AuthError::Malformed => write!(f, "malformed token"),
+            AuthError::UnsupportedAlgorithm => write!(f, "unsupported token algorithm"),
+            AuthError::InvalidSignature => write!(f, "invalid token signature"),
+            AuthError::Expired => write!(f, "expired token"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_bearer, sign, verify, AuthError, Claims};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn generate_secret() -> String {
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes).unwrap();
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn claims(room: Option<&str>) -> Claims {
+        Claims {
+            sub: Some("alice".into()),
+            exp: NOW + 60,
+            room: room.map(String::from),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let secret = generate_secret();
+        let token = sign(&claims(None), &secret);
+        assert_eq!(verify(&token, &secret, NOW), Ok(claims(None)));
+        assert_eq!(
+            verify(&token, &generate_secret(), NOW),
+            Err(AuthError::InvalidSignature)
+        );
+        assert_eq!(verify(&token, &secret, NOW + 60), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn tampered_tokens() {
+        let secret = generate_secret();
+        let token = sign(&claims(Some("room")), &secret);
+        let (header, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+
+        // Claims widened to every room, with the original signature.
+        let forged = format!(
+            "{}.{}.{}",
+            header,
+            URL_SAFE_NO_PAD.encode(r#"{"exp":1800000000}"#),
+            signature
+        );
+        assert_eq!(
+            verify(&forged, &secret, NOW),
+            Err(AuthError::InvalidSignature)
+        );
+
+        // An unsigned token.
+        let unsigned = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(r#"{"exp":1800000000}"#)
+        );
+        assert_eq!(
+            verify(&unsigned, &secret, NOW),
+            Err(AuthError::UnsupportedAlgorithm)
+        );
+        assert_eq!(verify("token", &secret, NOW), Err(AuthError::Malformed));
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        assert!(is_bearer("Bearer metrics-token", "metrics-token"));
+        assert!(!is_bearer("Bearer metrics-tokem", "metrics-token"));
+        assert!(!is_bearer("Bearer metrics", "metrics-token"));
+        assert!(!is_bearer("metrics-token", "metrics-token"));
+        assert!(!is_bearer("", "metrics-token"));
+    }
+
+    #[test]
+    fn invites() {
+        assert!(claims(Some("room")).allows_room("room"));
+        assert!(!claims(Some("room")).allows_room("another room"));
+        assert!(claims(None).allows_room("any room"));
+    }
+}
@@ -0,0 +1,28 @@
+//! Health checks and build information for uptime monitoring and deploy checks.
+
+use crate::state::State;
+use serde::Serialize;
+
+/// Build of the running worker, served by `/version`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Version {
+    pub(crate) version: &'static str,
+    /// Short hash of the git commit the worker is built from, or "unknown".
+    pub(crate) git_hash: &'static str,
+    pub(crate) protocol_version: u32,
+}
+
+impl Version {
+    pub(crate) fn current() -> Version {
+        Version {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("GIT_HASH"),
+            protocol_version: protocol::PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// Tells whether the worker can serve calls, that is its state backend answers.
+pub(crate) async fn is_ready(state: &State) -> bool {
+    state.ping().await
+}
@@ -0,0 +1,112 @@
+//! ICE server configuration handed to peers, including short-lived TURN credentials.
+//!
+//! TURN credentials follow the TURN REST API convention supported by TURN servers such as coturn
+//! (`use-auth-secret`): the username is `<expiry timestamp>:<user>`, the credential is the base64 encoded
+//! HMAC-SHA1 of the username keyed by a secret shared with the TURN server.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use protocol::IceServer;
+use sha1::Sha1;
+
+/// User part of TURN usernames.
+const TURN_USER: &str = "hangout";
+
+/// Default lifetime of TURN credentials in seconds. Credentials are only checked when allocating a relay,
+/// so they need to outlive the setup of a call, not the call.
+pub(crate) const DEFAULT_TURN_TTL: u64 = 30 * 60;
+
+/// TURN server settings from worker environment.
+#[derive(Debug)]
+pub(crate) struct TurnConfig {
+    pub(crate) urls: Vec<String>,
+    pub(crate) secret: String,
+    /// Lifetime of minted credentials in seconds.
+    pub(crate) ttl: u64,
+}
+
+/// Builds ICE servers for a peer, TURN credentials expire `ttl` seconds after `now`, which is a unix timestamp.
+pub(crate) fn ice_servers(
+    stun_urls: Vec<String>,
+    turn: Option<TurnConfig>,
+    now: u64,
+) -> Vec<IceServer> {
+    let mut servers = Vec::new();
+    if !stun_urls.is_empty() {
+        servers.push(IceServer {
+            urls: stun_urls,
+            username: None,
+            credential: None,
+        });
+    }
+    if let Some(turn) = turn.filter(|turn| !turn.urls.is_empty()) {
+        let username = format!("{}:{}", now + turn.ttl, TURN_USER);
+        let credential = turn_credential(&turn.secret, &username);
+        servers.push(IceServer {
+            urls: turn.urls,
+            username: Some(username),
+            credential: Some(credential),
+        });
+    }
+    servers
+}
+
+/// Splits a comma separated list of urls from a worker variable.
+pub(crate) fn parse_urls(var: &str) -> Vec<String> {
+    var.split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn turn_credential(secret: &str, username: &str) -> String {
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(username.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ice_servers, parse_urls, turn_credential, TurnConfig};
+
+    #[test]
+    fn credential() {
+        // Generated with: echo -n "1700000000:hangout" | openssl dgst -sha1 -hmac secret -binary | base64
+        assert_eq!(
+            turn_credential("secret", "1700000000:hangout"),
+            "7Xg+8aY5GoNNs250okPxDcZSydM="
+        );
+    }
+
+    #[test]
+    fn stun_and_turn_servers() {
+        let turn = TurnConfig {
+            urls: vec!["turn:turn.example.com:3478".into()],
+            secret: "secret".into(),
+            ttl: 600,
+        };
+        let servers = ice_servers(
+            vec!["stun:stun.example.com:3478".into()],
+            Some(turn),
+            1_699_999_400,
+        );
+
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].urls, ["stun:stun.example.com:3478"]);
+        assert!(servers[0].username.is_none());
+        assert_eq!(servers[1].username.as_deref(), Some("1700000000:hangout"));
+        assert_eq!(
+            servers[1].credential.as_deref(),
+            Some("7Xg+8aY5GoNNs250okPxDcZSydM=")
+        );
+    }
+
+    #[test]
+    fn without_turn() {
+        let servers = ice_servers(parse_urls("stun:a:3478, stun:b:3478,"), None, 0);
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].urls, ["stun:a:3478", "stun:b:3478"]);
+    }
+}
@@ -1,21 +1,64 @@
+mod assets;
+mod auth;
+mod health;
+mod ice;
+mod log;
+mod metrics;
+mod rate_limit;
+mod security;
 mod session;
 mod state;
 mod utils;
 
+use auth::{AuthError, Claims};
+use ice::TurnConfig;
+use log::Logger;
+use rate_limit::RateLimiter;
 use session::Session;
+use signaling::{room, CloseRoomError};
 use state::State;
+use wasm_bindgen::prelude::wasm_bindgen;
 use worker::{
-    console_debug, event, Context, Date, Env, Headers, Request, Response, Result, RouteContext,
-    Router, WebSocket, WebSocketPair,
+    console_log, worker_sys, Context, Cors, Date, Env, Headers, Method, Request, Response, Result,
+    RouteContext, Router, WebSocketPair,
 };
 
-#[event(fetch, respond_with_errors)]
-pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    log_request(&req);
+/// STUN server used when `STUN_URLS` is not configured.
+const DEFAULT_STUN_URL: &str = "stun:stun.l.google.com:19302";
+
+/// Entry point of the worker, the glue `#[event(fetch, respond_with_errors)]` would generate, except that
+/// precompressed assets are passed to the runtime as they are.
+#[wasm_bindgen]
+pub async fn fetch(
+    req: worker_sys::Request,
+    env: Env,
+    ctx: worker_sys::Context,
+) -> worker_sys::Response {
+    let response = match main(Request::from(req), env, Context::new(ctx)).await {
+        Ok(response) => response,
+        Err(error) => {
+            console_log!("{}", &error);
+            Response::error(error.to_string(), 500).expect("500 is a valid status code")
+        }
+    };
+    assets::into_edge_response(response)
+}
+
+async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
     utils::set_panic_hook();
+    log::set_level(
+        env.var("LOG_LEVEL")
+            .ok()
+            .map(|var| var.to_string())
+            .as_deref(),
+    );
+    let logger = Logger::request(req.headers().get("CF-Ray")?);
+    let started_at = Date::now().as_millis();
+    let (method, path) = (req.method(), req.path());
+    let region = req.cf().region();
 
-    let router = Router::new();
-    router
+    let router = Router::with_data(logger.clone());
+    let response = router
         .on_async("/signal", |req, ctx| async move {
             // For WebSocket connection flow, see: https://www.wallarm.com/what/a-simple-explanation-of-what-a-websocket-is#:~:text=In%20WebSocket%2C%20communication%20occurs%20at,party%20to%20terminate%20the%20connection.
             if !req
@@ -26,97 +69,332 @@ pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
             {
                 return Response::error("Expected Upgrade: websocket", 426);
             }
+            if !is_allowed_origin(&req, &ctx)? {
+                ctx.data.info("refused a connection from another origin");
+                return Response::error("Forbidden", 403);
+            }
+
+            // Refuse unauthenticated clients before any session state is touched.
+            let claims = match auth_secret(&ctx) {
+                Some(secret) => match authenticate(&req, &secret) {
+                    Ok(claims) => Some(claims),
+                    Err(error) => {
+                        ctx.data.info(format!("refused a connection: {}", error));
+                        return Response::error("Unauthorized", 401);
+                    }
+                },
+                None => None,
+            };
+
+            // Configuration is read before accepting, a session can't report errors once the socket is upgraded.
+            let config = ctx
+                .secret("PASSPHRASE_SECRET")
+                .and_then(|secret| Ok((secret.to_string(), new_state(&ctx)?)));
+            let (passphrase_secret, state) = match config {
+                Ok(config) => config,
+                Err(error) => {
+                    ctx.data
+                        .error(format!("signal server is not configured: {}", error));
+                    return Response::error("signal server is not configured", 500);
+                }
+            };
+            let client_ip = client_ip(&req)?;
 
             let WebSocketPair { client, server } = WebSocketPair::new()?;
 
             server.accept()?;
-            wasm_bindgen_futures::spawn_local(handle_websocket(server, ctx));
+            let log = ctx.data.session();
+            let limiter = RateLimiter::new(state.clone(), client_ip, log.clone());
+            let session = Session::new(server, state, passphrase_secret, limiter, claims, log);
+            wasm_bindgen_futures::spawn_local(session.start());
 
             Response::from_websocket(client)
         })
-        .on_async("/", |req, ctx| async {
-            let asset = handle_assets(req, ctx).await;
-            Response::from_html(&asset)
+        .get("/healthz", |_req, _ctx| Response::ok("ok"))
+        .get_async("/readyz", |_req, ctx| async move {
+            if health::is_ready(&new_state(&ctx)?).await {
+                Response::ok("ready")
+            } else {
+                Response::error("state backend is unreachable", 503)
+            }
+        })
+        .get("/version", |_req, _ctx| {
+            Response::from_json(&health::Version::current())
+        })
+        .get_async("/metrics", |req, ctx| async move {
+            // Metrics are public unless the `METRICS_TOKEN` secret is set.
+            if let Ok(token) = ctx.secret("METRICS_TOKEN") {
+                let authorization = req.headers().get("Authorization")?.unwrap_or_default();
+                if !auth::is_bearer(&authorization, &token.to_string()) {
+                    return Response::error("Unauthorized", 401);
+                }
+            }
+            match metrics::Metrics::new(new_state(&ctx)?).export().await {
+                Some(text) => {
+                    let mut headers = Headers::new();
+                    headers.set("Content-Type", "text/plain; version=0.0.4")?;
+                    Ok(Response::ok(text)?.with_headers(headers))
+                }
+                None => Response::error("could not read metrics", 503),
+            }
         })
-        .on_async("/pkg/peer.js", |req, ctx| async {
-            let asset = handle_assets(req, ctx).await;
-            let mut headers = Headers::new();
-            headers
-                .set("Content-Type", "application/javascript")
-                .unwrap();
-            Response::ok(&asset).map(|response| response.with_headers(headers))
+        .get_async("/ice-servers", |req, ctx| async move {
+            // TURN credentials relay traffic on our TURN server, they are minted for peers of the worker only.
+            if !is_allowed_origin(&req, &ctx)? {
+                ctx.data.info("refused ICE servers to another origin");
+                return Response::error("Forbidden", 403);
+            }
+            let cors = cors(&req, &ctx, Method::Get)?;
+            if let Some(secret) = auth_secret(&ctx) {
+                if let Err(error) = authenticate(&req, &secret) {
+                    ctx.data.info(format!("refused ICE servers: {}", error));
+                    return Response::error("Unauthorized", 401)?.with_cors(&cors);
+                }
+            }
+            let limiter = RateLimiter::new(new_state(&ctx)?, client_ip(&req)?, ctx.data.clone());
+            let limit = &rate_limit::ICE_SERVERS_PER_IP;
+            if let Err(error) = limiter
+                .check_ip(limit, Date::now().as_millis() / 1000)
+                .await
+            {
+                ctx.data.info("refused ICE servers over rate limit");
+                let mut response = Response::error("Too Many Requests", 429)?;
+                if let Some(retry_after) = error.retry_after {
+                    response
+                        .headers_mut()
+                        .set("Retry-After", &retry_after.to_string())?;
+                }
+                return response.with_cors(&cors);
+            }
+            let servers = handle_ice_servers(&ctx);
+            Response::from_json(&servers)?.with_cors(&cors)
         })
-        .on_async("/pkg/peer_bg.wasm", |req, ctx| async {
-            let asset = handle_wasm_asset(req, ctx).await;
-            let mut headers = Headers::new();
-            headers.set("Content-Type", "application/wasm").unwrap();
-            Response::from_bytes(asset).map(|response| response.with_headers(headers))
+        .post("/rooms", |req, ctx| {
+            let code = room::generate_code();
+            let id = room::room_id(&ctx.secret("PASSPHRASE_SECRET")?.to_string(), &code);
+            // Authenticated clients get an invite to the room to share along with its code,
+            // holders of invites can't create rooms.
+            let invite = match auth_secret(&ctx) {
+                Some(secret) => match authenticate(&req, &secret) {
+                    Ok(claims) if claims.room.is_none() => Some(issue_invite(&secret, &id, claims)),
+                    _ => {
+                        return Response::error("Unauthorized", 401)?.with_cors(&cors(
+                            &req,
+                            &ctx,
+                            Method::Post,
+                        )?)
+                    }
+                },
+                None => None,
+            };
+            let room = protocol::RoomCode { code, id, invite };
+            Response::from_json(&room)?.with_cors(&cors(&req, &ctx, Method::Post)?)
+        })
+        .get_async("/rooms/:id", |req, ctx| async move {
+            let id = match ctx.param("id") {
+                Some(id) if room::is_room_id(id) => id.clone(),
+                _ => return Response::error("Not Found", 404),
+            };
+            match signaling::room_status(&new_state(&ctx)?, &id).await {
+                Ok(status) => {
+                    Response::from_json(&status)?.with_cors(&cors(&req, &ctx, Method::Get)?)
+                }
+                Err(error) => {
+                    ctx.data
+                        .error(format!("could not read room status: {}", error));
+                    Response::error("could not read room status", 503)
+                }
+            }
+        })
+        .options("/rooms/:id", |req, ctx| {
+            // Preflight of closing a room from another allowed origin.
+            Response::empty()?
+                .with_status(204)
+                .with_cors(&cors(&req, &ctx, Method::Delete)?)
+        })
+        .delete_async("/rooms/:id", |req, ctx| async move {
+            // Browsers only show the status of a cross-origin failure to pages if it has CORS headers too.
+            let cors = cors(&req, &ctx, Method::Delete)?;
+            let id = match ctx.param("id") {
+                Some(id) if room::is_room_id(id) => id.clone(),
+                _ => return Response::error("Not Found", 404)?.with_cors(&cors),
+            };
+            // The creator proves it with its resume token.
+            let token = req
+                .headers()
+                .get("Authorization")?
+                .and_then(|header| header.strip_prefix("Bearer ").map(String::from));
+            let Some(token) = token else {
+                return Response::error("Unauthorized", 401)?.with_cors(&cors);
+            };
+            let state = match new_state(&ctx) {
+                Ok(state) => state,
+                Err(error) => {
+                    ctx.data.error(format!("could not close room: {}", error));
+                    return Response::error("could not close room", 500)?.with_cors(&cors);
+                }
+            };
+            let response = match signaling::close_room(&state, &id, &token).await {
+                Ok(()) => Response::empty()?.with_status(204),
+                Err(CloseRoomError::Forbidden) => Response::error("Forbidden", 403)?,
+                Err(CloseRoomError::Store(error)) => {
+                    ctx.data.error(format!("could not close room: {}", error));
+                    Response::error("could not close room", 503)?
+                }
+            };
+            response.with_cors(&cors)
         })
+        .or_else_any_method_async(
+            "/*path",
+            |req, ctx| async move { handle_asset(req, ctx).await },
+        )
         .run(req, env)
-        .await
+        .await?;
+
+    logger
+        .entry(log::Level::Info, "handled a request")
+        .field("method", method.to_string())
+        .field("path", path)
+        .field("region", region.unwrap_or_else(|| "unknown".into()))
+        .field("status", response.status_code())
+        .latency_ms(Date::now().as_millis().saturating_sub(started_at))
+        .emit();
+    Ok(response)
 }
 
-/// A WebSocket server handler.
-async fn handle_websocket(ws: WebSocket, ctx: RouteContext<()>) {
-    let upstash_redis_url = ctx
-        .secret("UPSTASH_REDIS_URL")
-        .expect("expect UPSTASH_REDIS_URL");
-    let upstash_redis_token = ctx
-        .secret("UPSTASH_REDIS_TOKEN")
-        .expect("expect UPSTASH_REDIS_TOKEN");
-    // Initiates state.
-    let state = State::new(
+/// Initiates state with the `UPSTASH_REDIS_URL` and `UPSTASH_REDIS_TOKEN` secrets.
+fn new_state(ctx: &RouteContext<Logger>) -> Result<State> {
+    let upstash_redis_url = ctx.secret("UPSTASH_REDIS_URL")?;
+    let upstash_redis_token = ctx.secret("UPSTASH_REDIS_TOKEN")?;
+    Ok(State::new(
         &upstash_redis_url.to_string(),
         &upstash_redis_token.to_string(),
-    );
-    let session = Session::new(ws, state);
-    session.start().await;
+    ))
+}
+
+/// Address of the client, set by Cloudflare.
+fn client_ip(req: &Request) -> Result<String> {
+    Ok(req
+        .headers()
+        .get("CF-Connecting-IP")?
+        .unwrap_or_else(|| "unknown".into()))
+}
+
+/// Returns the secret of tokens, authentication is required only if the `AUTH_SECRET` secret is set.
+fn auth_secret(ctx: &RouteContext<Logger>) -> Option<String> {
+    ctx.secret("AUTH_SECRET")
+        .ok()
+        .map(|secret| secret.to_string())
 }
 
-async fn handle_assets(req: Request, ctx: RouteContext<()>) -> String {
-    let extension = match req.path().as_str() {
-        "/" => "html",
-        "/pkg/peer.js" => "js",
-        _ => "html",
+/// Verifies the token of a request, taken from the `token` query parameter or the `Authorization` header.
+fn authenticate(req: &Request, secret: &str) -> std::result::Result<Claims, AuthError> {
+    let from_query = req.url().ok().and_then(|url| {
+        url.query_pairs()
+            .find(|(name, _)| name == "token")
+            .map(|(_, token)| token.into_owned())
+    });
+    let token = match from_query {
+        Some(token) => token,
+        None => req
+            .headers()
+            .get("Authorization")
+            .ok()
+            .flatten()
+            .and_then(|header| header.strip_prefix("Bearer ").map(String::from))
+            .ok_or(AuthError::Missing)?,
     };
-    console_debug!(
-        "request path: {}, path extension: {}",
-        req.path(),
-        extension
-    );
+    auth::verify(&token, secret, Date::now().as_millis() / 1000)
+}
+
+/// Issues an invite to a room, on behalf of the holder of a token.
+fn issue_invite(secret: &str, room_id: &str, claims: Claims) -> String {
+    let invite = Claims {
+        sub: claims.sub,
+        exp: Date::now().as_millis() / 1000 + auth::INVITE_TTL,
+        room: Some(room_id.into()),
+    };
+    auth::sign(&invite, secret)
+}
 
-    let kv_store = ctx.kv("__STATIC_CONTENT").unwrap();
-    let list = kv_store.list().execute().await.unwrap();
-    let key = list.keys.iter().find(|&key| key.name.ends_with(extension));
-    match key {
-        Some(key) => kv_store
-            .get(&key.name)
-            .text()
-            .await
-            .unwrap()
-            .unwrap_or_default(),
-        None => "".into(),
+/// Returns ICE servers configured by `STUN_URLS`, `TURN_URLS`, `TURN_TTL` variables and `TURN_SECRET` secret.
+/// TURN servers are left out if either `TURN_URLS` or `TURN_SECRET` is missing.
+fn handle_ice_servers(ctx: &RouteContext<Logger>) -> Vec<protocol::IceServer> {
+    let stun_urls = ctx
+        .var("STUN_URLS")
+        .map(|var| ice::parse_urls(&var.to_string()))
+        .unwrap_or_else(|_| vec![DEFAULT_STUN_URL.into()]);
+    let turn = match (ctx.var("TURN_URLS"), ctx.secret("TURN_SECRET")) {
+        (Ok(urls), Ok(secret)) => Some(TurnConfig {
+            urls: ice::parse_urls(&urls.to_string()),
+            secret: secret.to_string(),
+            ttl: ctx
+                .var("TURN_TTL")
+                .ok()
+                .and_then(|ttl| ttl.to_string().parse().ok())
+                .unwrap_or(ice::DEFAULT_TURN_TTL),
+        }),
+        _ => None,
+    };
+    ice::ice_servers(stun_urls, turn, Date::now().as_millis() / 1000)
+}
+
+/// Serves an asset of the site bucket, answering conditional requests by ETag.
+async fn handle_asset(req: Request, ctx: RouteContext<Logger>) -> Result<Response> {
+    if !matches!(req.method(), Method::Get | Method::Head) {
+        return Response::error("Method Not Allowed", 405);
+    }
+    let accept_encoding = req.headers().get("Accept-Encoding")?.unwrap_or_default();
+    let asset = match assets::lookup(&req.path(), &accept_encoding) {
+        Some(asset) => asset,
+        None => return Response::error("Not Found", 404),
+    };
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", asset.content_type())?;
+    headers.set("Cache-Control", asset.cache_control())?;
+    headers.set("ETag", &asset.etag())?;
+    if asset.is_compressible() {
+        headers.set("Vary", "Accept-Encoding")?;
+    }
+    security::apply_headers(&mut headers)?;
+    if let Some(if_none_match) = req.headers().get("If-None-Match")? {
+        if asset.is_not_modified(&if_none_match) {
+            return Ok(Response::empty()?.with_status(304).with_headers(headers));
+        }
+    }
+    if let Some(encoding) = asset.encoding {
+        headers.set("Content-Encoding", encoding.as_str())?;
+    }
+
+    let kv_store = ctx.kv("__STATIC_CONTENT")?;
+    match kv_store.get(&asset.key).bytes().await? {
+        Some(body) => Ok(Response::from_bytes(body)?.with_headers(headers)),
+        None => Response::error("Not Found", 404),
     }
 }
 
-async fn handle_wasm_asset(_req: Request, ctx: RouteContext<()>) -> Vec<u8> {
-    let kv_store = ctx.kv("__STATIC_CONTENT").unwrap();
-    let list = kv_store.list().execute().await.unwrap();
-    let key = list.keys.iter().find(|&key| key.name.ends_with("wasm"));
-    kv_store
-        .get(&key.unwrap().name)
-        .bytes()
-        .await
-        .unwrap()
-        .unwrap()
+/// Tells whether a request comes from the worker's own origin or one listed in the `ALLOWED_ORIGINS` variable.
+fn is_allowed_origin(req: &Request, ctx: &RouteContext<Logger>) -> Result<bool> {
+    let allowed = ctx
+        .var("ALLOWED_ORIGINS")
+        .map(|var| security::parse_origins(&var.to_string()))
+        .unwrap_or_default();
+    let own_origin = req.url()?.origin().ascii_serialization();
+    Ok(security::is_allowed_origin(
+        req.headers().get("Origin")?.as_deref(),
+        &own_origin,
+        &allowed,
+    ))
 }
 
-fn log_request(req: &Request) {
-    console_debug!(
-        "{} - [{}], located at: {:?}, within: {}",
-        Date::now().to_string(),
-        req.path(),
-        req.cf().coordinates().unwrap_or_default(),
-        req.cf().region().unwrap_or_else(|| "unknown region".into())
-    )
+/// CORS headers of API routes, peers may be served from another allowed origin during development.
+fn cors(req: &Request, ctx: &RouteContext<Logger>, method: Method) -> Result<Cors> {
+    let cors = Cors::new()
+        .with_methods(vec![method])
+        .with_allowed_headers(vec!["Authorization"]);
+    match req.headers().get("Origin")? {
+        Some(origin) if is_allowed_origin(req, ctx)? => Ok(cors.with_origins(vec![origin])),
+        _ => Ok(cors),
+    }
 }
@@ -0,0 +1,256 @@
+//! Structured logging, one JSON object per line, filtered by the `LOG_LEVEL` variable.
+//!
+//! Entries of a request carry its id, entries of a WebSocket connection also carry a session id and the role
+//! of the party, so that the lines of a call can be correlated. Messages are never logged with their data,
+//! which holds passphrases, tokens and session descriptions. As a safety net, session descriptions and IP
+//! addresses in log messages are redacted.
+
+use protocol::Event;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    cell::Cell,
+    net::{Ipv4Addr, Ipv6Addr},
+    rc::Rc,
+};
+use worker::{console_error, console_log, console_warn, Date};
+
+/// Level of entries logged when `LOG_LEVEL` is not set.
+const DEFAULT_LEVEL: Level = Level::Info;
+
+thread_local! {
+    static MAX_LEVEL: Cell<Level> = const { Cell::new(DEFAULT_LEVEL) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Logs entries of a request, or of a WebSocket connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Logger {
+    request_id: Option<Rc<str>>,
+    session_id: Option<Rc<str>>,
+    /// Role of the party, known once it joins.
+    role: Rc<Cell<Option<&'static str>>>,
+}
+
+/// An entry being built, logged by `emit`.
+#[must_use]
+pub(crate) struct Entry<'a> {
+    logger: &'a Logger,
+    level: Level,
+    message: String,
+    fields: Map<String, Value>,
+}
+
+/// Sets the level of logged entries, from the value of `LOG_LEVEL`.
+pub(crate) fn set_level(level: Option<&str>) {
+    let level = level.and_then(Level::parse).unwrap_or(DEFAULT_LEVEL);
+    MAX_LEVEL.with(|max_level| max_level.set(level));
+}
+
+impl Level {
+    fn parse(level: &str) -> Option<Level> {
+        match level.trim().to_ascii_lowercase().as_str() {
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warn" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+}
+
+impl Logger {
+    /// A logger of a request, identified by the `CF-Ray` header if any.
+    pub(crate) fn request(request_id: Option<String>) -> Logger {
+        Logger {
+            request_id: Some(request_id.unwrap_or_else(random_id).into()),
+            ..Logger::default()
+        }
+    }
+
+    /// A logger of a WebSocket connection of the request, with a new session id.
+    pub(crate) fn session(&self) -> Logger {
+        Logger {
+            request_id: self.request_id.clone(),
+            session_id: Some(random_id().into()),
+            role: Rc::default(),
+        }
+    }
+
+    /// Tags the following entries of the session with the role of the party.
+    pub(crate) fn set_role(&self, role: &'static str) {
+        self.role.set(Some(role));
+    }
+
+    pub(crate) fn entry(&self, level: Level, message: impl Into<String>) -> Entry<'_> {
+        Entry {
+            logger: self,
+            level,
+            message: message.into(),
+            fields: Map::new(),
+        }
+    }
+
+    pub(crate) fn debug(&self, message: impl Into<String>) {
+        self.entry(Level::Debug, message).emit();
+    }
+
+    pub(crate) fn info(&self, message: impl Into<String>) {
+        self.entry(Level::Info, message).emit();
+    }
+
+    pub(crate) fn warn(&self, message: impl Into<String>) {
+        self.entry(Level::Warn, message).emit();
+    }
+
+    pub(crate) fn error(&self, message: impl Into<String>) {
+        self.entry(Level::Error, message).emit();
+    }
+}
+
+impl Entry<'_> {
+    pub(crate) fn event(self, event: Event) -> Self {
+        self.field("event", format!("{:?}", event))
+    }
+
+    pub(crate) fn latency_ms(self, latency_ms: u64) -> Self {
+        self.field("latency_ms", latency_ms)
+    }
+
+    pub(crate) fn field(mut self, name: &str, value: impl Into<Value>) -> Self {
+        let value = match value.into() {
+            Value::String(text) => Value::String(redact(&text)),
+            value => value,
+        };
+        self.fields.insert(name.into(), value);
+        self
+    }
+
+    /// Logs the entry if its level is enabled.
+    pub(crate) fn emit(self) {
+        if self.level < MAX_LEVEL.with(Cell::get) {
+            return;
+        }
+        let level = self.level;
+        let line = self.render(Date::now().as_millis());
+        match level {
+            Level::Debug | Level::Info => console_log!("{}", line),
+            Level::Warn => console_warn!("{}", line),
+            Level::Error => console_error!("{}", line),
+        }
+    }
+
+    /// Renders the entry as a JSON line, `timestamp` is in milliseconds.
+    fn render(self, timestamp: u64) -> String {
+        let mut record = Map::new();
+        record.insert("ts".into(), timestamp.into());
+        record.insert("level".into(), serde_json::to_value(self.level).unwrap());
+        record.insert("msg".into(), redact(&self.message).into());
+        if let Some(request_id) = &self.logger.request_id {
+            record.insert("request_id".into(), request_id.as_ref().into());
+        }
+        if let Some(session_id) = &self.logger.session_id {
+            record.insert("session_id".into(), session_id.as_ref().into());
+        }
+        if let Some(role) = self.logger.role.get() {
+            record.insert("role".into(), role.into());
+        }
+        record.extend(self.fields);
+        Value::Object(record).to_string()
+    }
+}
+
+/// Redacts session descriptions and IP addresses of a text.
+fn redact(text: &str) -> String {
+    if text.contains("v=0") && text.contains("a=") {
+        return "[sdp]".into();
+    }
+    let mut redacted = String::with_capacity(text.len());
+    let mut token = String::new();
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if c.is_ascii_hexdigit() || c == '.' || c == ':' {
+            token.push(c);
+            continue;
+        }
+        redacted.push_str(&redact_address(&token));
+        token.clear();
+        redacted.push(c);
+    }
+    redacted.pop();
+    redacted
+}
+
+fn redact_address(token: &str) -> String {
+    let address = token.trim_end_matches(['.', ':']);
+    if address.parse::<Ipv4Addr>().is_ok() || address.parse::<Ipv6Addr>().is_ok() {
+        format!("[ip]{}", &token[address.len()..])
+    } else {
+        token.into()
+    }
+}
+
+/// A random id of 16 hex characters.
+fn random_id() -> String {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("could not generate random bytes");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redact, Level, Logger};
+    use protocol::Event;
+    use serde_json::Value;
+
+    #[test]
+    fn levels() {
+        assert_eq!(Level::parse("WARN"), Some(Level::Warn));
+        assert_eq!(Level::parse("verbose"), None);
+        assert!(Level::Debug < Level::Info && Level::Warn < Level::Error);
+    }
+
+    #[test]
+    fn redaction() {
+        assert_eq!(
+            redact("candidate:1 1 udp 2122260223 192.168.1.7 54321 typ host."),
+            "candidate:1 1 udp 2122260223 [ip] 54321 typ host."
+        );
+        assert_eq!(
+            redact("from 2001:db8::1, to 10.0.0.1."),
+            "from [ip], to [ip]."
+        );
+        assert_eq!(
+            redact("v=0\r\no=- 1 2 IN IP4 127.0.0.1\r\na=setup"),
+            "[sdp]"
+        );
+        assert_eq!(redact("at 12:30, room deadbeef"), "at 12:30, room deadbeef");
+    }
+
+    #[test]
+    fn json_lines() {
+        let logger = Logger::request(Some("ray".into())).session();
+        logger.set_role("caller");
+        let line = logger
+            .entry(Level::Info, "relayed a message")
+            .event(Event::Offer)
+            .latency_ms(12)
+            .render(1_700_000_000_000);
+
+        let record: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["level"], "info");
+        assert_eq!(record["request_id"], "ray");
+        assert_eq!(record["session_id"].as_str().unwrap().len(), 16);
+        assert_eq!(record["role"], "caller");
+        assert_eq!(record["event"], "Offer");
+        assert_eq!(record["latency_ms"], 12);
+        assert!(!line.contains('\n'));
+    }
+}
@@ -0,0 +1,289 @@
+//! Counters of calls, exported in Prometheus text format by `/metrics`.
+//!
+//! Workers don't share memory between requests, so counters live in a Redis hash and are incremented
+//! with `HINCRBY`. A session counts what happens during it locally, and writes it in one transaction
+//! when it ends, so that relaying messages doesn't wait for Redis. Labels are event names and roles only,
+//! never passphrases or room ids.
+
+use crate::state::{Response as StateResponse, Result as StateResult, State};
+use protocol::Event;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+/// Redis hash of all counters, its fields are Prometheus series such as `name{label="value"}`.
+pub(crate) const METRICS_KEY: &str = "metrics";
+
+/// Counter of failed commands on Redis, incremented by `State` itself.
+pub(crate) const STORAGE_ERRORS: &str = "hangout_storage_errors_total";
+
+const SESSIONS_CREATED: &str = "hangout_sessions_created_total";
+const SESSIONS_RESUMED: &str = "hangout_sessions_resumed_total";
+const ROLES_ASSIGNED: &str = "hangout_roles_assigned_total";
+const MESSAGES_RELAYED: &str = "hangout_messages_relayed_total";
+const SESSION_DURATION: &str = "hangout_session_duration_seconds";
+
+/// Upper bounds of the session duration histogram in seconds.
+const DURATION_BUCKETS: [u64; 7] = [10, 30, 60, 300, 900, 1800, 3600];
+
+/// Exported metrics as name, type and help.
+const FAMILIES: [(&str, &str, &str); 6] = [
+    (
+        SESSIONS_CREATED,
+        "counter",
+        "WebSocket sessions accepted by signal server.",
+    ),
+    (
+        SESSIONS_RESUMED,
+        "counter",
+        "Sessions resumed with a resume token.",
+    ),
+    (
+        ROLES_ASSIGNED,
+        "counter",
+        "Roles assigned to joining parties.",
+    ),
+    (
+        MESSAGES_RELAYED,
+        "counter",
+        "Messages relayed between parties, by event.",
+    ),
+    (STORAGE_ERRORS, "counter", "Failed commands on Redis."),
+    (
+        SESSION_DURATION,
+        "histogram",
+        "Durations of WebSocket sessions.",
+    ),
+];
+
+/// Something counted.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Metric {
+    SessionCreated,
+    SessionResumed,
+    /// A role is assigned, labelled "caller" or "callee".
+    RoleAssigned(&'static str),
+    MessageRelayed(Event),
+    /// A session ends after a number of seconds.
+    SessionEnded(u64),
+}
+
+/// Records and exports metrics. Clones share the counts waiting to be flushed.
+#[derive(Debug, Clone)]
+pub(crate) struct Metrics {
+    state: State,
+    pending: Rc<RefCell<Counts>>,
+}
+
+/// Increments of fields of the metrics hash, not written yet.
+#[derive(Debug, Default)]
+struct Counts(HashMap<String, u64>);
+
+impl Metrics {
+    pub(crate) fn new(state: State) -> Metrics {
+        Metrics {
+            state,
+            pending: Rc::default(),
+        }
+    }
+
+    /// Counts a metric right away, failures are only logged by state.
+    pub(crate) async fn record(&self, metric: Metric) {
+        self.state
+            .hincrby_fields(METRICS_KEY, &metric.fields())
+            .await;
+    }
+
+    /// Counts a metric locally, it is written by the next flush.
+    pub(crate) fn count(&self, metric: Metric) {
+        self.pending.borrow_mut().add(metric);
+    }
+
+    /// Writes the local counts in one transaction, failures are only logged by state.
+    pub(crate) async fn flush(&self) {
+        let fields = self.pending.borrow_mut().take();
+        if !fields.is_empty() {
+            self.state.hincrby_fields(METRICS_KEY, &fields).await;
+        }
+    }
+
+    /// Renders all counters in Prometheus text format, or None if they could not be read.
+    pub(crate) async fn export(&self) -> Option<String> {
+        match self.state.hgetall(METRICS_KEY).await {
+            StateResponse::Result(StateResult::Array(fields)) => {
+                let counters: Vec<(&str, &str)> = fields
+                    .chunks_exact(2)
+                    .map(|pair| (pair[0].as_str(), pair[1].as_str()))
+                    .collect();
+                Some(render(&counters))
+            }
+            StateResponse::Result(StateResult::Null) => Some(render(&[])),
+            _ => None,
+        }
+    }
+}
+
+impl Counts {
+    fn add(&mut self, metric: Metric) {
+        for (field, increment) in metric.fields() {
+            *self.0.entry(field).or_default() += increment;
+        }
+    }
+
+    /// Takes the counts, leaving none.
+    fn take(&mut self) -> Vec<(String, u64)> {
+        self.0.drain().collect()
+    }
+}
+
+impl Metric {
+    /// Fields of the metrics hash to increment, with their increments.
+    fn fields(&self) -> Vec<(String, u64)> {
+        match self {
+            Metric::SessionCreated => vec![(SESSIONS_CREATED.into(), 1)],
+            Metric::SessionResumed => vec![(SESSIONS_RESUMED.into(), 1)],
+            Metric::RoleAssigned(role) => {
+                vec![(format!("{}{{role=\"{}\"}}", ROLES_ASSIGNED, role), 1)]
+            }
+            Metric::MessageRelayed(event) => {
+                vec![(format!("{}{{event=\"{:?}\"}}", MESSAGES_RELAYED, event), 1)]
+            }
+            Metric::SessionEnded(duration) => {
+                // Buckets are cumulative, a duration falls in every bucket above it.
+                let mut fields: Vec<(String, u64)> = DURATION_BUCKETS
+                    .iter()
+                    .filter(|&&bound| *duration <= bound)
+                    .map(|bound| {
+                        (
+                            format!("{}_bucket{{le=\"{}\"}}", SESSION_DURATION, bound),
+                            1,
+                        )
+                    })
+                    .collect();
+                fields.push((format!("{}_bucket{{le=\"+Inf\"}}", SESSION_DURATION), 1));
+                fields.push((format!("{}_sum", SESSION_DURATION), *duration));
+                fields.push((format!("{}_count", SESSION_DURATION), 1));
+                fields
+            }
+        }
+    }
+}
+
+/// Renders counters as series of their metric families, in a stable order.
+fn render(counters: &[(&str, &str)]) -> String {
+    let mut output = String::new();
+    for (name, kind, help) in FAMILIES {
+        output.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+        let mut series: Vec<&(&str, &str)> = counters
+            .iter()
+            .filter(|(field, _)| {
+                field
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(['{', '_']))
+            })
+            .collect();
+        series.sort_by_key(|(field, _)| sort_key(field));
+        for (field, value) in series {
+            output.push_str(&format!("{} {}\n", field, value));
+        }
+    }
+    output
+}
+
+/// Orders histogram buckets by their bound, other series by name.
+fn sort_key(field: &str) -> (String, u64) {
+    match field.split_once("_bucket{le=\"") {
+        Some((name, bound)) => {
+            let bound = bound.trim_end_matches("\"}").parse().unwrap_or(u64::MAX);
+            (format!("{}_bucket", name), bound)
+        }
+        None => (field.to_string(), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, Counts, Metric};
+    use protocol::Event;
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            Metric::MessageRelayed(Event::Offer).fields(),
+            [(
+                "hangout_messages_relayed_total{event=\"Offer\"}".to_string(),
+                1
+            )]
+        );
+        let fields = Metric::SessionEnded(120).fields();
+        let names: Vec<&str> = fields.iter().map(|(field, _)| field.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "hangout_session_duration_seconds_bucket{le=\"300\"}",
+                "hangout_session_duration_seconds_bucket{le=\"900\"}",
+                "hangout_session_duration_seconds_bucket{le=\"1800\"}",
+                "hangout_session_duration_seconds_bucket{le=\"3600\"}",
+                "hangout_session_duration_seconds_bucket{le=\"+Inf\"}",
+                "hangout_session_duration_seconds_sum",
+                "hangout_session_duration_seconds_count",
+            ]
+        );
+        assert_eq!(fields[5].1, 120);
+    }
+
+    #[test]
+    fn local_counts() {
+        let mut counts = Counts::default();
+        counts.add(Metric::MessageRelayed(Event::IceCandidate));
+        counts.add(Metric::MessageRelayed(Event::IceCandidate));
+        counts.add(Metric::SessionEnded(5));
+
+        let fields = counts.take();
+        assert_eq!(fields.len(), Metric::SessionEnded(5).fields().len() + 1);
+        assert!(fields.contains(&(
+            "hangout_messages_relayed_total{event=\"IceCandidate\"}".to_string(),
+            2
+        )));
+        assert!(fields.contains(&("hangout_session_duration_seconds_sum".to_string(), 5)));
+        assert!(counts.take().is_empty());
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let mut counters = Vec::new();
+        for metric in [
+            Metric::SessionCreated,
+            Metric::RoleAssigned("caller"),
+            Metric::SessionEnded(5),
+        ] {
+            counters.extend(metric.fields());
+        }
+        counters.reverse();
+        let counters: Vec<(&str, String)> = counters
+            .iter()
+            .map(|(field, value)| (field.as_str(), value.to_string()))
+            .collect();
+        let counters: Vec<(&str, &str)> = counters
+            .iter()
+            .map(|(field, value)| (*field, value.as_str()))
+            .collect();
+
+        let text = render(&counters);
+        assert!(text.contains(
+            "# TYPE hangout_sessions_created_total counter\nhangout_sessions_created_total 1\n"
+        ));
+        assert!(text.contains("hangout_roles_assigned_total{role=\"caller\"} 1\n"));
+        assert!(text.contains(
+            "hangout_session_duration_seconds_bucket{le=\"10\"} 1\n\
+             hangout_session_duration_seconds_bucket{le=\"30\"} 1\n"
+        ));
+        assert!(text.contains(
+            "hangout_session_duration_seconds_bucket{le=\"+Inf\"} 1\n\
+             hangout_session_duration_seconds_count 1\n\
+             hangout_session_duration_seconds_sum 5\n"
+        ));
+        assert!(text.contains("# TYPE hangout_storage_errors_total counter\n# HELP"));
+    }
+}
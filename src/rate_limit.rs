@@ -0,0 +1,183 @@
+//! Fixed window rate limits of signaling, counted in the state backend so that they hold across worker instances.
+
+use crate::{
+    log::Logger,
+    state::{Response as StateResponse, Result as StateResult, State},
+};
+use protocol::{ErrorCode, ServerError};
+
+/// A number of requests allowed per window of time.
+#[derive(Debug)]
+pub(crate) struct Limit {
+    /// Name of the limited requests in counter keys.
+    name: &'static str,
+    max: u32,
+    /// Length of a window in seconds.
+    window: u32,
+}
+
+/// WebSocket connections of a client IP.
+pub(crate) const CONNECTIONS_PER_IP: Limit = Limit {
+    name: "connect",
+    max: 20,
+    window: 60,
+};
+
+/// Joins and resumes of a room, whoever makes them, room ids stand for passphrases. It keeps a room from being
+/// joined over and over, but doesn't bound passphrase guessing: every guess is another room.
+/// `CONNECTIONS_PER_IP` and `FAILED_JOINS_PER_IP` bound guessing.
+pub(crate) const CONNECTIONS_PER_ROOM: Limit = Limit {
+    name: "room-connect",
+    max: 10,
+    window: 60,
+};
+
+/// Failed joins of a client IP, with a weak passphrase or to a full room. Clients failing to join
+/// over and over are likely guessing passphrases.
+pub(crate) const FAILED_JOINS_PER_IP: Limit = Limit {
+    name: "failed-join",
+    max: 5,
+    window: 600,
+};
+
+/// Signaling messages of a client IP, a call takes a few dozens.
+pub(crate) const MESSAGES_PER_IP: Limit = Limit {
+    name: "message",
+    max: 120,
+    window: 60,
+};
+
+/// Signaling messages to a room from both of its parties, whichever IPs they come from.
+pub(crate) const MESSAGES_PER_ROOM: Limit = Limit {
+    name: "room-message",
+    max: 240,
+    window: 60,
+};
+
+/// ICE servers fetched by a client IP, each fetch mints TURN credentials. A peer fetches them once a call.
+pub(crate) const ICE_SERVERS_PER_IP: Limit = Limit {
+    name: "ice-servers",
+    max: 10,
+    window: 60,
+};
+
+/// Counts requests of a client against limits.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    state: State,
+    /// Address of the client, from the `CF-Connecting-IP` header.
+    client_ip: String,
+    log: Logger,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(state: State, client_ip: String, log: Logger) -> RateLimiter {
+        RateLimiter {
+            state,
+            client_ip,
+            log,
+        }
+    }
+
+    /// Counts a request of the client, `now` is a unix timestamp.
+    pub(crate) async fn check_ip(&self, limit: &Limit, now: u64) -> Result<(), ServerError> {
+        self.check(limit, &self.client_ip, now).await
+    }
+
+    /// Counts a request on a room, whoever makes it.
+    pub(crate) async fn check_room(
+        &self,
+        limit: &Limit,
+        room_id: &str,
+        now: u64,
+    ) -> Result<(), ServerError> {
+        self.check(limit, room_id, now).await
+    }
+
+    /// Fails if the client already exceeded a limit, without counting a request.
+    pub(crate) async fn check_ip_exceeded(
+        &self,
+        limit: &Limit,
+        now: u64,
+    ) -> Result<(), ServerError> {
+        let key = limit.key(&self.client_ip, now);
+        match self.state.get(&key).await {
+            StateResponse::Result(StateResult::Str(count))
+                if count.parse::<u32>().is_ok_and(|count| count >= limit.max) =>
+            {
+                Err(limit.exceeded(now))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a request of the client, without checking the limit.
+    pub(crate) async fn record_ip(&self, limit: &Limit, now: u64) {
+        let key = limit.key(&self.client_ip, now);
+        self.state.incr_with_expiry(&key, limit.window).await;
+    }
+
+    async fn check(&self, limit: &Limit, subject: &str, now: u64) -> Result<(), ServerError> {
+        let key = limit.key(subject, now);
+        match self.state.incr_with_expiry(&key, limit.window).await {
+            Some(count) if count > limit.max => Err(limit.exceeded(now)),
+            Some(_) => Ok(()),
+            None => {
+                // Rather let a request through than lock everybody out while the state backend fails.
+                self.log.warn(format!(
+                    "could not count {} request, allowing it",
+                    limit.name
+                ));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Limit {
+    /// Key of the counter of a subject in the window of `now`.
+    fn key(&self, subject: &str, now: u64) -> String {
+        format!(
+            "ratelimit:{}:{}:{}",
+            self.name,
+            subject,
+            now / self.window as u64
+        )
+    }
+
+    /// The error reported for a request over the limit.
+    fn exceeded(&self, now: u64) -> ServerError {
+        ServerError {
+            code: ErrorCode::RateLimited,
+            retry_after: Some(self.retry_after(now)),
+        }
+    }
+
+    /// Seconds until the window of `now` ends.
+    fn retry_after(&self, now: u64) -> u32 {
+        self.window - (now % self.window as u64) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CONNECTIONS_PER_ROOM;
+
+    #[test]
+    fn windows() {
+        assert_eq!(
+            CONNECTIONS_PER_ROOM.key("room", 1_700_000_000),
+            "ratelimit:room-connect:room:28333333"
+        );
+        assert_eq!(
+            CONNECTIONS_PER_ROOM.key("room", 1_700_000_039),
+            "ratelimit:room-connect:room:28333333"
+        );
+        assert_eq!(
+            CONNECTIONS_PER_ROOM.key("room", 1_700_000_040),
+            "ratelimit:room-connect:room:28333334"
+        );
+        assert_eq!(CONNECTIONS_PER_ROOM.retry_after(1_700_000_000), 40);
+        assert_eq!(CONNECTIONS_PER_ROOM.retry_after(1_700_000_040), 60);
+    }
+}
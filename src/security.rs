@@ -0,0 +1,144 @@
+//! Origin checks of cross-origin requests, and security headers of pages and assets.
+//!
+//! Browsers send an `Origin` header with WebSocket upgrades and cross-origin fetches, but don't enforce
+//! CORS on WebSockets, so `/signal` checks it itself. Pages of the worker's own origin are always allowed,
+//! other origins must be listed in the `ALLOWED_ORIGINS` variable.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use worker::{Headers, Result};
+
+/// The page served by the worker, hashes of its inline scripts are allowed by the content security policy.
+const INDEX_HTML: &str = include_str!("../static/index.html");
+
+/// Headers set on pages and assets, besides the content security policy.
+const SECURITY_HEADERS: [(&str, &str); 7] = [
+    // Required by cross-origin isolation, pages of the worker only load their own resources.
+    ("Cross-Origin-Opener-Policy", "same-origin"),
+    ("Cross-Origin-Embedder-Policy", "require-corp"),
+    (
+        "Permissions-Policy",
+        "camera=(self), microphone=(self), display-capture=(self), geolocation=(), payment=(), usb=()",
+    ),
+    (
+        "Strict-Transport-Security",
+        "max-age=63072000; includeSubDomains",
+    ),
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    // Page urls carry tokens and invites in their query.
+    ("Referrer-Policy", "no-referrer"),
+];
+
+/// Parses a comma separated list of origins, `*` allows any origin.
+pub(crate) fn parse_origins(origins: &str) -> Vec<String> {
+    origins
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/'))
+        .filter(|origin| !origin.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Tells whether a request from `origin` is allowed, `own_origin` is the origin of the worker.
+/// Requests without an `Origin` header don't come from browsers and are allowed.
+pub(crate) fn is_allowed_origin(
+    origin: Option<&str>,
+    own_origin: &str,
+    allowed: &[String],
+) -> bool {
+    match origin {
+        Some(origin) => {
+            origin == own_origin
+                || allowed
+                    .iter()
+                    .any(|allowed| allowed == "*" || allowed == origin)
+        }
+        None => true,
+    }
+}
+
+/// Sets security headers of a page or asset. API responses and WebSocket upgrades go without them,
+/// browsers only apply them to documents and the resources they load.
+pub(crate) fn apply_headers(headers: &mut Headers) -> Result<()> {
+    headers.set("Content-Security-Policy", &content_security_policy())?;
+    for (name, value) in SECURITY_HEADERS {
+        headers.set(name, value)?;
+    }
+    Ok(())
+}
+
+/// Allows resources of the worker's own origin only, and the inline scripts of its page.
+fn content_security_policy() -> String {
+    let script_hashes: String = inline_script_hashes(INDEX_HTML)
+        .iter()
+        .map(|hash| format!(" 'sha256-{}'", hash))
+        .collect();
+    format!(
+        "default-src 'self'; script-src 'self' 'wasm-unsafe-eval'{}; img-src 'self' blob: data:; \
+         object-src 'none'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'",
+        script_hashes
+    )
+}
+
+/// Returns base64 encoded SHA-256 hashes of the inline scripts of a page.
+fn inline_script_hashes(html: &str) -> Vec<String> {
+    let mut hashes = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("<script") {
+        let Some(tag_end) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start..start + tag_end];
+        let content_start = start + tag_end + 1;
+        let Some(content_len) = rest[content_start..].find("</script>") else {
+            break;
+        };
+        if !tag.contains("src=") {
+            let content = &rest[content_start..content_start + content_len];
+            hashes.push(STANDARD.encode(Sha256::digest(content)));
+        }
+        rest = &rest[content_start + content_len..];
+    }
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inline_script_hashes, is_allowed_origin, parse_origins, INDEX_HTML};
+
+    #[test]
+    fn origins() {
+        let allowed = parse_origins(" https://a.example.com/, http://localhost:8080,");
+        assert_eq!(allowed, ["https://a.example.com", "http://localhost:8080"]);
+
+        let own = "https://hangout.example.com";
+        assert!(is_allowed_origin(Some(own), own, &[]));
+        assert!(is_allowed_origin(None, own, &[]));
+        assert!(is_allowed_origin(
+            Some("http://localhost:8080"),
+            own,
+            &allowed
+        ));
+        assert!(!is_allowed_origin(
+            Some("https://evil.example.com"),
+            own,
+            &allowed
+        ));
+        assert!(is_allowed_origin(
+            Some("https://evil.example.com"),
+            own,
+            &parse_origins("*")
+        ));
+    }
+
+    #[test]
+    fn script_hashes() {
+        let html = r#"<script src="a.js"></script><p></p><script type="module">alert(1)</script>"#;
+        assert_eq!(
+            inline_script_hashes(html),
+            ["bhHHL3z2vDgxUt0W3dWQOrprscmda2Y5pLsLg4GF+pI="]
+        );
+        assert_eq!(inline_script_hashes(INDEX_HTML).len(), 1);
+    }
+}
@@ -1,224 +1,254 @@
-use crate::state::{Response as StateResponse, Result as StateResult, State};
+//! Signaling sessions on the worker: WebSockets of the Workers runtime, Redis as the store, and the rate
+//! limits, authentication, metrics and logs of the worker as hooks.
+
+use crate::{
+    auth::Claims,
+    log::{Level, Logger},
+    metrics::{Metric, Metrics},
+    rate_limit::{self, RateLimiter},
+    state::State,
+};
 use futures::StreamExt;
-use futures_channel::mpsc::{self, Receiver, Sender};
+use protocol::{ErrorCode, Role};
+use signaling::{Rejection, Report};
 use std::time::Duration;
-use worker::{console_debug, console_error, console_log, Delay, WebSocket, WebsocketEvent};
+use worker::{Date, Delay, EventStream, WebSocket, WebsocketEvent};
 
 #[derive(Debug)]
 pub(crate) struct Session {
     websocket: WebSocket,
     state: State,
-
-    signal_sender: Sender<()>,
-    signal_receiver: Receiver<()>,
+    /// Key of the keyed hash deriving room ids from passphrases.
+    passphrase_secret: String,
+    limiter: RateLimiter,
+    /// Claims of the token the client connected with, if authentication is required.
+    claims: Option<Claims>,
+    metrics: Metrics,
+    log: Logger,
 }
 
-/// A session registry.
-#[derive(Debug, Clone)]
-struct Registry {
-    /// The passphrase key to be set on Redis.
-    passphrase_key: String,
-    /// Sender channel key to be set on Redis.
-    send_channel_key: String,
-    /// Receiver channel key to be set on Redis.
-    receive_channel_key: String,
-}
+/// The WebSocket of a client.
+struct Client(WebSocket);
 
-#[derive(Debug)]
-enum Role {
-    Caller,
-    Callee,
+struct Messages<'ws>(EventStream<'ws>);
+
+struct WorkerClock;
+
+/// Limits and observes a session the way the worker does for all requests.
+struct Hooks {
+    limiter: RateLimiter,
+    claims: Option<Claims>,
+    metrics: Metrics,
+    log: Logger,
 }
 
 impl Session {
     /// Creates a new session.
-    pub(crate) fn new(websocket: WebSocket, state: State) -> Session {
-        let (tx, rx) = mpsc::channel(0);
+    pub(crate) fn new(
+        websocket: WebSocket,
+        state: State,
+        passphrase_secret: String,
+        limiter: RateLimiter,
+        claims: Option<Claims>,
+        log: Logger,
+    ) -> Session {
         Session {
             websocket,
+            metrics: Metrics::new(state.clone()),
             state,
-            signal_sender: tx,
-            signal_receiver: rx,
+            passphrase_secret,
+            limiter,
+            claims,
+            log,
         }
     }
 
-    pub(crate) async fn start(mut self) {
-        let mut registry = None;
-
-        // Read the first message to get passphrase.
-        let mut event_stream = self.websocket.events().expect("could not open stream");
-        let first_event = event_stream.next().await.expect("expect first message");
-        match first_event.expect("received error in websocket") {
-            WebsocketEvent::Message(msg) => {
-                // Get the passphrase as text.
-                let passphrase = msg.text().expect("expect a passphrase");
-                console_debug!("got passphrase: {:#?}", passphrase);
-
-                // Try to set this passphrase as key in Redis.
-                match self
-                    .state
-                    .set_nx(&Registry::passphrase_key(&passphrase))
-                    .await
-                {
-                    StateResponse::Result(result) => {
-                        console_debug!("got set result: {:#?}", result);
-
-                        // Get the role.
-                        // Response to client according to result.
-                        let role = match result {
-                            StateResult::Str(value) if value.eq("OK") => {
-                                // The passphrase doesn't exist before.
-                                console_debug!("this is caller");
-                                Role::Caller
-                            }
-                            StateResult::Null => {
-                                // The passphrase already exists.
-                                console_debug!("this is callee");
-                                Role::Callee
-                            }
-                            _ => {
-                                console_debug!("unknown result: {:#?}", result);
-                                return;
-                            }
-                        };
-                        Self::response_based_on_role(&role, &self.websocket);
-                        registry = Some(Registry::new(passphrase, role));
-                    }
-                    StateResponse::Error(error) => {
-                        console_error!("could not execute set command on state: {}", error);
-                        // Signal subscription task to exit.
-                        self.signal_sender.close_channel();
-                        return;
-                    }
-                }
-
-                // Once set the passphrase, subscribe to the other party's state channel immediately.
-                wasm_bindgen_futures::spawn_local(Self::subscribe(
-                    self.state.clone(),
-                    self.websocket.clone(),
-                    registry.clone().expect("expect a registry"),
-                    self.signal_receiver,
-                ));
-            }
-            WebsocketEvent::Close(_) => console_log!("WebSocket connection closed"),
+    pub(crate) async fn start(self) {
+        // Listen before counting the connection, so that the first message isn't missed meanwhile.
+        let mut messages = Messages(self.websocket.events().expect("could not open stream"));
+
+        let hooks = Hooks {
+            limiter: self.limiter.clone(),
+            claims: self.claims,
+            metrics: self.metrics.clone(),
+            log: self.log.clone(),
+        };
+        let session = signaling::Session::new(
+            self.state,
+            Client(self.websocket.clone()),
+            WorkerClock,
+            hooks,
+            self.passphrase_secret,
+        );
+
+        let connection_limit = &rate_limit::CONNECTIONS_PER_IP;
+        if let Err(error) = self.limiter.check_ip(connection_limit, now()).await {
+            self.log.info("refused a connection over rate limit");
+            session.reject(&error, protocol::CLOSE_RATE_LIMITED);
+            return;
         }
+        self.metrics.record(Metric::SessionCreated).await;
+        let started_at = now();
 
-        // Forward client messages to sate channel.
-        while let Some(event) = event_stream.next().await {
-            match event.expect("received error in websocket") {
-                WebsocketEvent::Message(msg) => {
-                    console_debug!("received message: {:#?}", msg);
-
-                    if let Some(content) = msg.text() {
-                        let channel_key = &registry
-                            .as_ref()
-                            .expect("expect a registry")
-                            .send_channel_key;
-                        // Send message to state channel.
-                        let response = self.state.send(channel_key, &content).await;
-                        if let StateResponse::Error(error) = response {
-                            console_error!("failed to send message to state channel: {}", error);
-
-                            // Signal subscription task to exit.
-                            self.signal_sender.close_channel();
-                            return;
-                        }
-                        console_debug!("a message is forwarded to state channel");
-                    }
-                }
-                WebsocketEvent::Close(_) => {
-                    console_log!("WebSocket connection closed");
-
-                    // Signal subscription task to exit.
-                    self.signal_sender.close_channel();
-                    return;
-                }
-            }
+        session.run(&mut messages).await;
+
+        self.metrics
+            .count(Metric::SessionEnded(now().saturating_sub(started_at)));
+        self.metrics.flush().await;
+    }
+}
+
+impl signaling::Hooks for Hooks {
+    async fn check_join(&self) -> Result<(), Rejection> {
+        let failed_join_limit = &rate_limit::FAILED_JOINS_PER_IP;
+        if let Err(error) = self
+            .limiter
+            .check_ip_exceeded(failed_join_limit, now())
+            .await
+        {
+            self.log
+                .info("refused a join of a client failing to join over and over");
+            return Err(Rejection::RateLimited(error));
         }
+        Ok(())
     }
 
-    async fn subscribe(
-        state: State,
-        websocket: WebSocket,
-        registry: Registry,
-        mut rx: Receiver<()>,
-    ) {
-        loop {
-            let response = state.receive(&registry.receive_channel_key).await;
-            match response {
-                StateResponse::Error(error) => {
-                    console_error!("error occurred when receive from state channel: {}", error);
-                    return;
-                }
-                StateResponse::Result(result) => match result {
-                    StateResult::Str(msg) => websocket.send_with_str(msg).unwrap(),
-                    StateResult::Null => {
-                        // Sleep for 1000 ms.
-                        Delay::from(Duration::from_millis(1000)).await;
-                    }
-                    _ => {}
-                },
+    async fn check_room(&self, room_id: &str) -> Result<(), Rejection> {
+        self.log.debug(format!("joining room: {}", room_id));
+
+        if let Some(claims) = &self.claims {
+            if !claims.allows_room(room_id) {
+                return Err(Rejection::Refused(ErrorCode::Unauthorized));
             }
+        }
 
-            // Exit subscription after parent task exists.
-            if let Ok(None) = rx.try_next() {
-                // Delete the passphrase stored in Redis during session.
-                let keys = [
-                    registry.passphrase_key.as_str(),
-                    registry.send_channel_key.as_str(),
-                ];
-                state.del_keys(&keys).await;
-                break;
+        let connection_limit = &rate_limit::CONNECTIONS_PER_ROOM;
+        if let Err(error) = self
+            .limiter
+            .check_room(connection_limit, room_id, now())
+            .await
+        {
+            self.log
+                .info("refused a connection to a room over rate limit");
+            return Err(Rejection::RateLimited(error));
+        }
+        Ok(())
+    }
+
+    async fn check_message(&self, room_id: &str) -> Result<(), protocol::ServerError> {
+        self.limiter
+            .check_ip(&rate_limit::MESSAGES_PER_IP, now())
+            .await?;
+        self.limiter
+            .check_room(&rate_limit::MESSAGES_PER_ROOM, room_id, now())
+            .await
+    }
+
+    async fn report(&self, report: Report) {
+        match report {
+            Report::Joined(role) => {
+                self.log.set_role(label(role));
+                self.log.info("joined a room");
+            }
+            Report::RoleAssigned(role) => self.metrics.count(Metric::RoleAssigned(label(role))),
+            Report::Resumed { role, persisted } => {
+                self.log.set_role(label(role));
+                self.log
+                    .info(format!("resumed session, persisted {} keys", persisted));
+                self.metrics.count(Metric::SessionResumed);
+            }
+            Report::ResumeRefused { room_closed: false } => {
+                self.log.info("resume token is unknown or expired")
+            }
+            Report::ResumeRefused { room_closed: true } => {
+                self.log.info("room of the resume token is closed")
+            }
+            Report::ResumeInUse => self
+                .log
+                .info("session of the resume token is still connected"),
+            Report::JoinRefused(code) => {
+                // Counted as a failure of the client.
+                self.log.info(format!("refused a join: {:?}", code));
+                self.limiter
+                    .record_ip(&rate_limit::FAILED_JOINS_PER_IP, now())
+                    .await;
+            }
+            Report::Knocked => self.log.info("knocked on a room"),
+            Report::LeftWhileKnocking => self.log.info("left while knocking"),
+            Report::NotAdmitted => self.log.info("was not admitted"),
+            Report::AnsweredKnock(admitted) => self.log.info(format!(
+                "answered a knock: {}",
+                if admitted { "admit" } else { "deny" }
+            )),
+            Report::KnockAnswerDropped => self.log.info("dropped an answer to a knock from callee"),
+            Report::MessageDropped(code) => self.log.info(format!("dropped a message: {:?}", code)),
+            Report::MessageRelayed {
+                event,
+                size,
+                latency_ms,
+            } => {
+                let entry = self
+                    .log
+                    .entry(Level::Debug, "a message is forwarded to state channel")
+                    .field("size", size)
+                    .latency_ms(latency_ms);
+                let Some(event) = event else {
+                    entry.emit();
+                    return;
+                };
+                entry.event(event).emit();
+                self.metrics.count(Metric::MessageRelayed(event));
             }
+            Report::StoreError { action, error } => self
+                .log
+                .error(format!("could not {} on state: {}", action, error)),
+            Report::Closed => self.log.info("WebSocket connection closed"),
         }
     }
+}
 
-    fn response_based_on_role(role: &Role, ws: &WebSocket) {
-        let message = match role {
-            Role::Caller => protocol::Message {
-                event: protocol::Event::Passphrase,
-                data: "1".into(),
-            },
-            Role::Callee => protocol::Message {
-                event: protocol::Event::Passphrase,
-                data: "0".into(),
-            },
-        };
-        let serialized_message = serde_json::to_string(&message).unwrap();
-        ws.send_with_str(&serialized_message).unwrap();
+impl signaling::Socket for Client {
+    fn send(&self, text: &str) -> Result<(), String> {
+        self.0
+            .send_with_str(text)
+            .map_err(|error| error.to_string())
+    }
+
+    fn close(&self, code: u16, reason: &str) {
+        self.0.close(Some(code), Some(reason)).ok();
     }
 }
 
-impl Registry {
-    fn new(passphrase: String, role: Role) -> Registry {
-        // A peer sends on its onw channel, receives on the other party's channel.
-        let (send_channel_key, receive_channel_key) = match role {
-            Role::Caller => (
-                Self::caller_channel_key(&passphrase),
-                Self::callee_channel_key(&passphrase),
-            ),
-            Role::Callee => (
-                Self::callee_channel_key(&passphrase),
-                Self::caller_channel_key(&passphrase),
-            ),
-        };
-        let passphrase_key = Self::passphrase_key(&passphrase);
-        Registry {
-            passphrase_key,
-            send_channel_key,
-            receive_channel_key,
+impl signaling::Incoming for Messages<'_> {
+    async fn next(&mut self) -> Option<String> {
+        while let Some(Ok(WebsocketEvent::Message(message))) = self.0.next().await {
+            if let Some(text) = message.text() {
+                return Some(text);
+            }
         }
+        None
     }
+}
 
-    fn passphrase_key(passphrase: &str) -> String {
-        format!("passphrase:{}", passphrase)
+impl signaling::Clock for WorkerClock {
+    fn now_ms(&self) -> u64 {
+        Date::now().as_millis()
     }
 
-    fn caller_channel_key(passphrase: &str) -> String {
-        format!("channel:caller:{}", passphrase)
+    async fn sleep(&self, duration: Duration) {
+        Delay::from(duration).await;
     }
+}
 
-    fn callee_channel_key(passphrase: &str) -> String {
-        format!("channel:callee:{}", passphrase)
+/// Label of a role in logs and metrics.
+fn label(role: Role) -> &'static str {
+    match role {
+        Role::Caller => "caller",
+        Role::Callee => "callee",
     }
 }
+
+/// Current unix timestamp in seconds.
+fn now() -> u64 {
+    Date::now().as_millis() / 1000
+}
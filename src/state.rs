@@ -1,8 +1,9 @@
 //! A Redis database backed state. Redis service is provided by Upstash with a RESTful API.
 
-use serde::Deserialize;
+use crate::{log::Logger, metrics};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use wasm_bindgen::JsValue;
-use worker::{console_debug, Fetch, Headers, Method, Request, RequestInit, Url};
+use worker::{Fetch, Headers, Method, Request, RequestInit, Url};
 
 /// A channel implemented based on Redis List data structure.
 #[derive(Debug, Clone)]
@@ -27,11 +28,11 @@ impl State {
         State { url, headers }
     }
 
-    /// Executes a specialized set command, the whole command is `set key "" nx`. Only the key matters.
-    /// The key should be prefixed with "passphrase" in order to avoid key name collision in Redis.
+    /// Executes a `set key value nx` command.
+    /// The key should be prefixed with "room" in order to avoid key name collision in Redis.
     /// Returns "OK" if value not exists else Null.
-    pub(crate) async fn set_nx(&self, key: &str) -> Response {
-        let cmd = ["set", key, "", "nx"];
+    pub(crate) async fn set_nx(&self, key: &str, value: &str) -> Response {
+        let cmd = ["set", key, value, "nx"];
         self.command(&cmd).await
     }
 
@@ -49,33 +50,226 @@ impl State {
         self.command(&cmd).await
     }
 
-    /// Deletes all keys relating to the party in a session.
-    pub(crate) async fn del_keys(&self, keys: &[&str]) -> Response {
-        let cmd = [&["del"], keys].concat();
+    /// Puts a received element back to the receiving end of a channel, so that it is the next one to be received.
+    pub(crate) async fn requeue(&self, key: &str, element: &str) -> Response {
+        let cmd = ["rpush", key, element];
         self.command(&cmd).await
     }
 
-    /// Executes any command on Redis.
+    /// Executes a `set key value` command.
+    pub(crate) async fn set(&self, key: &str, value: &str) -> Response {
+        let cmd = ["set", key, value];
+        self.command(&cmd).await
+    }
+
+    /// Executes a `get key` command. Returns Null if key not exists.
+    pub(crate) async fn get(&self, key: &str) -> Response {
+        let cmd = ["get", key];
+        self.command(&cmd).await
+    }
+
+    /// Executes a `del key...` command. Returns the number of keys deleted.
+    pub(crate) async fn del(&self, keys: &[&str]) -> Response {
+        let cmd: Vec<&str> = ["del"].iter().chain(keys).copied().collect();
+        self.command(&cmd).await
+    }
+
+    /// Sets a time to live on each of the keys, after which they are deleted.
+    /// Stops at the first key which fails.
+    pub(crate) async fn expire_keys(
+        &self,
+        keys: &[&str],
+        seconds: u32,
+    ) -> std::result::Result<(), String> {
+        let seconds = seconds.to_string();
+        for key in keys {
+            let cmd = ["expire", key, &seconds];
+            self.command(&cmd).await.into_result()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the time to live on each of the keys set by `expire_keys`.
+    /// Returns the number of keys whose time to live is actually removed.
+    pub(crate) async fn persist_keys(&self, keys: &[&str]) -> u32 {
+        let mut persisted = 0;
+        for key in keys {
+            let cmd = ["persist", key];
+            if let Response::Result(Result::Int(n)) = self.command(&cmd).await {
+                persisted += n;
+            }
+        }
+        persisted
+    }
+
+    /// Increments a counter, which expires `seconds` after its first increment.
+    /// Both run in one transaction, and the expiry is set unless the counter has one already,
+    /// so that no counter outlives its window. Returns the counter after increment, or None if it could not be incremented.
+    pub(crate) async fn incr_with_expiry(&self, key: &str, seconds: u32) -> Option<u32> {
+        let seconds = seconds.to_string();
+        let commands: [&[&str]; 2] = [&["incr", key], &["expire", key, &seconds, "nx"]];
+        match self.transaction(&commands).await.as_deref() {
+            Some([Response::Result(Result::Int(count)), Response::Result(_)]) => Some(*count),
+            _ => None,
+        }
+    }
+
+    /// Executes a `ping` command, tells whether Redis is reachable.
+    pub(crate) async fn ping(&self) -> bool {
+        let cmd = ["ping"];
+        matches!(
+            self.command(&cmd).await,
+            Response::Result(Result::Str(pong)) if pong == "PONG"
+        )
+    }
+
+    /// Executes a `hincrby key field increment` command for every field, in one transaction.
+    /// Tells whether the fields were incremented.
+    pub(crate) async fn hincrby_fields(&self, key: &str, fields: &[(String, u64)]) -> bool {
+        let increments: Vec<String> = fields
+            .iter()
+            .map(|(_, increment)| increment.to_string())
+            .collect();
+        let commands: Vec<[&str; 4]> = fields
+            .iter()
+            .zip(&increments)
+            .map(|((field, _), increment)| ["hincrby", key, field, increment])
+            .collect();
+        let commands: Vec<&[&str]> = commands.iter().map(|command| &command[..]).collect();
+        self.transaction(&commands).await.is_some()
+    }
+
+    /// Executes a `hgetall key` command. Returns fields and values one after the other.
+    pub(crate) async fn hgetall(&self, key: &str) -> Response {
+        let cmd = ["hgetall", key];
+        self.command(&cmd).await
+    }
+
+    /// Executes any command on Redis, counting failures.
     async fn command(&self, command: &[&str]) -> Response {
-        let body = serde_json::to_string(&command).unwrap();
-        console_debug!("command: {}", body);
+        let response = self.execute(command).await;
+        if let Response::Error(_) = response {
+            self.count_error().await;
+        }
+        response
+    }
 
+    /// Executes commands in a `multi`/`exec` transaction, counting failures.
+    /// Returns the response of every command, or None if the transaction failed as a whole.
+    async fn transaction(&self, commands: &[&[&str]]) -> Option<Vec<Response>> {
+        // Arguments hold keys and messages, only the commands are logged.
+        Logger::default().debug(format!(
+            "transaction: {:?}",
+            commands
+                .iter()
+                .map(|command| command[0])
+                .collect::<Vec<_>>()
+        ));
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .ok()?
+            .pop_if_empty()
+            .push("multi-exec");
+        match self.post(&url, &commands).await {
+            Ok(responses) => Some(responses),
+            Err(error) => {
+                Logger::default().debug(format!("transaction failed: {}", error));
+                self.count_error().await;
+                None
+            }
+        }
+    }
+
+    /// Counts a failure in metrics. Not counted again if this fails too, Redis is likely unreachable then.
+    async fn count_error(&self) {
+        let cmd = [
+            "hincrby",
+            metrics::METRICS_KEY,
+            metrics::STORAGE_ERRORS,
+            "1",
+        ];
+        self.execute(&cmd).await;
+    }
+
+    async fn execute(&self, command: &[&str]) -> Response {
+        // Arguments hold keys and messages, only the command is logged.
+        Logger::default().debug(format!("command: {}", command[0]));
+        self.post(&self.url, &command)
+            .await
+            .unwrap_or_else(Response::Error)
+    }
+
+    /// Posts a body to an Upstash endpoint, and reads its JSON response.
+    async fn post<T: DeserializeOwned>(
+        &self,
+        url: &Url,
+        body: &impl Serialize,
+    ) -> std::result::Result<T, String> {
+        let body = serde_json::to_string(body).unwrap();
         let mut request_init = RequestInit::new();
         request_init
             .with_method(Method::Post)
             .with_headers(self.headers.clone())
             .with_body(Some(JsValue::from_str(&body)));
 
-        let request = Request::new_with_init(self.url.as_str(), &request_init).unwrap();
-        let mut response = Fetch::Request(request).send().await.unwrap();
-        if !response.status_code().eq(&200) {
-            panic!(
+        let request = Request::new_with_init(url.as_str(), &request_init).unwrap();
+        let mut response = Fetch::Request(request)
+            .send()
+            .await
+            .map_err(|error| format!("request failed: {}", error))?;
+        // Upstash reports command errors in the body of non-200 responses too.
+        response.json().await.map_err(|_| {
+            format!(
                 "request not successful, status code: {}",
                 response.status_code()
             )
+        })
+    }
+}
+
+/// Redis commands of signaling sessions.
+impl signaling::Store for State {
+    async fn set_nx(&self, key: &str, value: &str) -> std::result::Result<bool, String> {
+        match State::set_nx(self, key, value).await.into_result()? {
+            Result::Str(value) if value == "OK" => Ok(true),
+            Result::Null => Ok(false),
+            result => Err(format!("unknown result: {:?}", result)),
         }
+    }
+
+    async fn set(&self, key: &str, value: &str) -> std::result::Result<(), String> {
+        State::set(self, key, value).await.into_result().map(drop)
+    }
+
+    async fn get(&self, key: &str) -> std::result::Result<Option<String>, String> {
+        Ok(State::get(self, key).await.into_result()?.into_str())
+    }
+
+    async fn del(&self, keys: &[&str]) -> std::result::Result<(), String> {
+        State::del(self, keys).await.into_result().map(drop)
+    }
+
+    async fn push(&self, key: &str, element: &str) -> std::result::Result<(), String> {
+        self.send(key, element).await.into_result().map(drop)
+    }
 
-        response.json().await.unwrap()
+    async fn pop(&self, key: &str) -> std::result::Result<Option<String>, String> {
+        Ok(self.receive(key).await.into_result()?.into_str())
+    }
+
+    async fn requeue(&self, key: &str, element: &str) -> std::result::Result<(), String> {
+        State::requeue(self, key, element)
+            .await
+            .into_result()
+            .map(drop)
+    }
+
+    async fn expire(&self, keys: &[&str], seconds: u32) -> std::result::Result<(), String> {
+        self.expire_keys(keys, seconds).await
+    }
+
+    async fn persist(&self, keys: &[&str]) -> std::result::Result<u32, String> {
+        Ok(self.persist_keys(keys).await)
     }
 }
 
@@ -94,11 +288,31 @@ pub(crate) enum Result {
     Null,
     Str(String),
     Int(u32),
+    Array(Vec<String>),
+}
+
+impl Response {
+    fn into_result(self) -> std::result::Result<Result, String> {
+        match self {
+            Response::Result(result) => Ok(result),
+            Response::Error(error) => Err(error),
+        }
+    }
+}
+
+impl Result {
+    /// The string of a result, None if it is anything else.
+    fn into_str(self) -> Option<String> {
+        match self {
+            Result::Str(value) => Some(value),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Response;
+    use super::{Response, Result};
 
     #[test]
     fn deserialize_response() {
@@ -113,5 +327,18 @@ mod tests {
 
         let int_result = r#"{"result": 1}"#;
         serde_json::from_str::<Response>(int_result).unwrap();
+
+        let array_result = r#"{"result": ["field", "1"]}"#;
+        serde_json::from_str::<Response>(array_result).unwrap();
+        // Transactions answer with the response of every command.
+        let transaction_result = r#"[{"result": 3}, {"result": 0}]"#;
+        let responses = serde_json::from_str::<Vec<Response>>(transaction_result).unwrap();
+        assert!(matches!(
+            responses.as_slice(),
+            [
+                Response::Result(Result::Int(3)),
+                Response::Result(Result::Int(0))
+            ]
+        ));
     }
 }
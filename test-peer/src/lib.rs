@@ -0,0 +1,412 @@
+//! A headless peer speaking the signaling protocol over WebSockets, to test whole caller and callee exchanges
+//! without a browser.
+//!
+//! Peers connect to `hangout-server` started in the test process on an ephemeral port, or to the signal server
+//! at `SIGNAL_URL`, such as `ws://127.0.0.1:8787/signal` of `wrangler dev`. Rate limits of the worker hold for
+//! test peers too, so only a few tests can run against it in a row.
+//!
+//! Peers fail the test by panicking whenever the server doesn't say what they expect in time.
+
+use futures::{SinkExt, StreamExt};
+use protocol::{ErrorCode, Event, Join, Message, PeerInfo, Role, ServerError};
+use std::{env, time::Duration};
+use tokio::{net::TcpStream, time};
+use tokio_tungstenite::{
+    tungstenite::{self, protocol::CloseFrame},
+    MaybeTlsStream, WebSocketStream,
+};
+
+/// How long a peer waits for the server before failing the test, the in-process server polls every 100 ms
+/// and the worker every second.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A peer connected to a signal server.
+#[derive(Debug)]
+pub struct TestPeer {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    room_id: Option<String>,
+    role: Option<Role>,
+    resume_token: Option<String>,
+}
+
+/// What a peer receives next.
+#[derive(Debug)]
+pub enum Received {
+    Message(Message),
+    /// The server closed the connection, with the close code if it gave one.
+    Closed(Option<u16>),
+}
+
+/// The WebSocket url of the signal server tests run against, starting `hangout-server` unless `SIGNAL_URL`
+/// is set. The server lives as long as the runtime of the test.
+pub async fn signal_url() -> String {
+    if let Ok(url) = env::var("SIGNAL_URL") {
+        return url;
+    }
+    let config = hangout_server::Config {
+        passphrase_secret: hangout_server::random_secret(),
+        static_dir: "static".into(),
+    };
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .expect("could not bind an ephemeral port");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, hangout_server::router(config))
+            .await
+            .expect("signal server failed");
+    });
+    format!("ws://{}/signal", addr)
+}
+
+/// A passphrase of a room nobody else joins, so that tests don't meet in the same room.
+pub fn passphrase() -> String {
+    signaling::room::generate_code()
+}
+
+/// What a peer tells about itself, a name only.
+pub fn info(name: &str) -> PeerInfo {
+    PeerInfo {
+        name: Some(name.into()),
+        ..PeerInfo::default()
+    }
+}
+
+impl TestPeer {
+    /// Connects to a signal server, without saying anything yet.
+    pub async fn connect(url: &str) -> TestPeer {
+        let (socket, _) = time::timeout(TIMEOUT, tokio_tungstenite::connect_async(url))
+            .await
+            .expect("timed out connecting to signal server")
+            .expect("could not connect to signal server");
+        TestPeer {
+            socket,
+            room_id: None,
+            role: None,
+            resume_token: None,
+        }
+    }
+
+    /// Connects and joins a room under a name, and waits for a role.
+    pub async fn join(url: &str, passphrase: &str, name: &str) -> TestPeer {
+        let mut peer = TestPeer::connect(url).await;
+        peer.send_join(&Join {
+            passphrase: passphrase.into(),
+            info: info(name),
+            knock: false,
+        })
+        .await;
+        peer.expect_role().await;
+        peer
+    }
+
+    /// Reconnects by a resume token, and waits for the role to be resumed.
+    /// Retries while the server holds the session for a connection it hasn't noticed as dropped yet.
+    pub async fn resume(url: &str, token: &str) -> TestPeer {
+        let resume = async {
+            loop {
+                let mut peer = TestPeer::connect(url).await;
+                peer.send(Event::Resume, token).await;
+                match peer.receive().await {
+                    Received::Message(message) if message.event == Event::Resume => {
+                        return (peer, message.data)
+                    }
+                    Received::Closed(Some(protocol::CLOSE_SESSION_IN_USE)) => {
+                        time::sleep(Duration::from_millis(50)).await
+                    }
+                    received => panic!("expected {:?}, received {:?}", Event::Resume, received),
+                }
+            }
+        };
+        let (mut peer, role) = time::timeout(TIMEOUT, resume)
+            .await
+            .expect("timed out resuming a session");
+        peer.role = Some(parse_role(&role));
+        peer.resume_token = Some(token.into());
+        peer
+    }
+
+    /// The id of the room the peer joined.
+    pub fn room_id(&self) -> Option<&str> {
+        self.room_id.as_deref()
+    }
+
+    /// The role the server assigned, once the peer joined or resumed.
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+
+    /// The token to resume the session with, once the peer joined.
+    pub fn resume_token(&self) -> Option<&str> {
+        self.resume_token.as_deref()
+    }
+
+    pub async fn send_join(&mut self, join: &Join) {
+        self.send(Event::Join, &serde_json::to_string(join).unwrap())
+            .await;
+    }
+
+    pub async fn send(&mut self, event: Event, data: &str) {
+        let message = Message {
+            event,
+            data: data.into(),
+        };
+        self.send_text(&serde_json::to_string(&message).unwrap())
+            .await;
+    }
+
+    /// Sends a raw text message, which doesn't have to be a protocol message.
+    pub async fn send_text(&mut self, text: &str) {
+        self.socket
+            .send(tungstenite::Message::text(text))
+            .await
+            .expect("could not send to signal server");
+    }
+
+    pub async fn offer(&mut self, sdp: &str) {
+        self.send(Event::Offer, sdp).await;
+    }
+
+    pub async fn answer(&mut self, sdp: &str) {
+        self.send(Event::Answer, sdp).await;
+    }
+
+    /// Sends ICE candidates one right after the other, the way a browser gathers them.
+    /// Returns the candidates in the order they were sent.
+    pub async fn candidate_burst(&mut self, count: usize) -> Vec<String> {
+        let candidates: Vec<String> = (0..count)
+            .map(|i| {
+                format!(
+                    "candidate:{} 1 udp 2122260223 192.0.2.1 {} typ host",
+                    i,
+                    50000 + i
+                )
+            })
+            .collect();
+        for candidate in &candidates {
+            self.send(Event::IceCandidate, candidate).await;
+        }
+        candidates
+    }
+
+    /// Waits for the next message or for the connection to close.
+    pub async fn receive(&mut self) -> Received {
+        loop {
+            let message = time::timeout(TIMEOUT, self.socket.next())
+                .await
+                .expect("timed out waiting for signal server");
+            match message {
+                Some(Ok(tungstenite::Message::Text(text))) => {
+                    let message = serde_json::from_str(&text)
+                        .unwrap_or_else(|_| panic!("not a protocol message: {}", text));
+                    return Received::Message(message);
+                }
+                Some(Ok(tungstenite::Message::Close(frame))) => {
+                    return Received::Closed(frame.map(|frame| frame.code.into()))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => return Received::Closed(None),
+            }
+        }
+    }
+
+    /// Waits for a message of an event, which must be the next one received. Returns its data.
+    pub async fn expect(&mut self, event: Event) -> String {
+        match self.receive().await {
+            Received::Message(message) if message.event == event => message.data,
+            received => panic!("expected {:?}, received {:?}", event, received),
+        }
+    }
+
+    /// Waits for a room id, a role and a resume token, the server's answer to a join.
+    pub async fn expect_role(&mut self) -> Role {
+        self.room_id = Some(self.expect(Event::Room).await);
+        let role = parse_role(&self.expect(Event::Passphrase).await);
+        self.role = Some(role);
+        self.resume_token = Some(self.expect(Event::ResumeToken).await);
+        role
+    }
+
+    /// Waits for the peer info of the other party.
+    pub async fn expect_peer_info(&mut self) -> PeerInfo {
+        let info = self.expect(Event::PeerInfo).await;
+        serde_json::from_str(&info).expect("invalid peer info")
+    }
+
+    /// Waits for an error, and returns its code.
+    pub async fn expect_error(&mut self) -> ErrorCode {
+        let error = self.expect(Event::Error).await;
+        serde_json::from_str::<ServerError>(&error)
+            .expect("invalid server error")
+            .code
+    }
+
+    /// Waits for the server to close the connection, and returns the close code.
+    pub async fn expect_close(&mut self) -> Option<u16> {
+        match self.receive().await {
+            Received::Closed(code) => code,
+            received => panic!("expected the connection to close, received {:?}", received),
+        }
+    }
+
+    /// Leaves by closing the connection the way a browser closes a page.
+    pub async fn disconnect(mut self) {
+        let frame = CloseFrame {
+            code: tungstenite::protocol::frame::coding::CloseCode::Normal,
+            reason: "".into(),
+        };
+        self.socket.close(Some(frame)).await.ok();
+    }
+
+    /// Drops the connection without closing it, the way a network failure does.
+    pub fn drop_connection(self) {
+        drop(self.socket);
+    }
+}
+
+fn parse_role(role: &str) -> Role {
+    match role {
+        "1" => Role::Caller,
+        "0" => Role::Callee,
+        _ => panic!("invalid role: {}", role),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{info, passphrase, signal_url, TestPeer};
+    use protocol::{ErrorCode, Event, Join, Role};
+
+    /// A caller and a callee in the same room, each told about the other.
+    async fn pair(url: &str) -> (TestPeer, TestPeer) {
+        let passphrase = passphrase();
+        let mut caller = TestPeer::join(url, &passphrase, "Ada").await;
+        let mut callee = TestPeer::join(url, &passphrase, "Grace").await;
+        assert_eq!(caller.role(), Some(Role::Caller));
+        assert_eq!(callee.role(), Some(Role::Callee));
+        assert_eq!(caller.room_id(), callee.room_id());
+        assert_eq!(caller.expect_peer_info().await, info("Grace"));
+        assert_eq!(callee.expect_peer_info().await, info("Ada"));
+        (caller, callee)
+    }
+
+    #[tokio::test]
+    async fn offer_answer() {
+        let url = signal_url().await;
+        let (mut caller, mut callee) = pair(&url).await;
+
+        caller.offer("offer sdp").await;
+        assert_eq!(callee.expect(Event::Offer).await, "offer sdp");
+        callee.answer("answer sdp").await;
+        assert_eq!(caller.expect(Event::Answer).await, "answer sdp");
+    }
+
+    #[tokio::test]
+    async fn offer_before_callee_joins() {
+        let url = signal_url().await;
+        let passphrase = passphrase();
+        let mut caller = TestPeer::join(&url, &passphrase, "Ada").await;
+        caller.offer("offer sdp").await;
+
+        let mut callee = TestPeer::join(&url, &passphrase, "Grace").await;
+        assert_eq!(callee.expect_peer_info().await, info("Ada"));
+        assert_eq!(callee.expect(Event::Offer).await, "offer sdp");
+    }
+
+    #[tokio::test]
+    async fn candidate_bursts() {
+        let url = signal_url().await;
+        let (mut caller, mut callee) = pair(&url).await;
+
+        // Both parties gather candidates at once, each receives the other's in order.
+        let (caller_candidates, callee_candidates) =
+            tokio::join!(caller.candidate_burst(30), callee.candidate_burst(30));
+        for candidate in caller_candidates {
+            assert_eq!(callee.expect(Event::IceCandidate).await, candidate);
+        }
+        for candidate in callee_candidates {
+            assert_eq!(caller.expect(Event::IceCandidate).await, candidate);
+        }
+    }
+
+    #[tokio::test]
+    async fn third_joiner() {
+        let url = signal_url().await;
+        let passphrase = passphrase();
+        let mut caller = TestPeer::join(&url, &passphrase, "Ada").await;
+        let mut callee = TestPeer::join(&url, &passphrase, "Grace").await;
+        caller.expect_peer_info().await;
+        callee.expect_peer_info().await;
+
+        let mut third = TestPeer::connect(&url).await;
+        third
+            .send_join(&Join {
+                passphrase,
+                info: info("Eve"),
+                knock: false,
+            })
+            .await;
+        assert_eq!(third.expect_error().await, ErrorCode::RoomFull);
+        assert_eq!(
+            third.expect_close().await,
+            Some(protocol::CLOSE_JOIN_REFUSED)
+        );
+
+        // The call goes on without the third party hearing of it.
+        caller.offer("offer sdp").await;
+        assert_eq!(callee.expect(Event::Offer).await, "offer sdp");
+    }
+
+    #[tokio::test]
+    async fn disconnect_and_resume() {
+        let url = signal_url().await;
+        let (mut caller, callee) = pair(&url).await;
+        let token = callee.resume_token().unwrap().to_string();
+        callee.drop_connection();
+
+        // Messages sent meanwhile are delivered once callee is back.
+        caller.offer("offer sdp").await;
+        let mut callee = TestPeer::resume(&url, &token).await;
+        assert_eq!(callee.role(), Some(Role::Callee));
+        assert_eq!(callee.expect(Event::Offer).await, "offer sdp");
+
+        caller.disconnect().await;
+        let mut invalid = TestPeer::connect(&url).await;
+        invalid.send(Event::Resume, "unknown").await;
+        assert_eq!(
+            invalid.expect_close().await,
+            Some(protocol::CLOSE_INVALID_RESUME_TOKEN)
+        );
+    }
+
+    #[tokio::test]
+    async fn knock_admitted() {
+        let url = signal_url().await;
+        let passphrase = passphrase();
+        let mut caller = TestPeer::connect(&url).await;
+        caller
+            .send_join(&Join {
+                passphrase: passphrase.clone(),
+                info: info("Ada"),
+                knock: true,
+            })
+            .await;
+        caller.expect_role().await;
+
+        let mut callee = TestPeer::connect(&url).await;
+        callee
+            .send_join(&Join {
+                passphrase,
+                info: info("Grace"),
+                knock: false,
+            })
+            .await;
+        let request = caller.expect(Event::JoinRequest).await;
+        assert_eq!(serde_json::from_str(&request).ok(), Some(info("Grace")));
+
+        caller.send(Event::Admit, "").await;
+        assert_eq!(callee.expect_role().await, Role::Callee);
+        assert_eq!(callee.expect_peer_info().await, info("Ada"));
+        assert_eq!(caller.expect_peer_info().await, info("Grace"));
+    }
+}